@@ -1,11 +1,17 @@
-use crate::AsKey;
+use crate::db::PriceStore;
+use crate::realm::{variant_key, Auction, Item, Market, MarketStats, PetSpecies};
+use crate::sales::SalesEstimate;
+use crate::{AsKey, Error};
 use actix::{Actor, Context, Handler, Message};
-use log::{error, info, trace};
+use log::{error, trace};
 
 #[derive(Debug)]
 /// Mandatory data for auction storage
 pub struct AuctionRow {
+    pub market: Market,
     pub item_id: u64,
+    /// See `ItemIden::variant`
+    pub variant: Option<String>,
     pub auction_id: u64,
     pub quantity: u16,
     pub unit_price: u64,
@@ -13,19 +19,22 @@ pub struct AuctionRow {
 
 impl AsKey for AuctionRow {
     fn id(&self) -> String {
-        self.item_id.to_string()
+        variant_key(self.item_id, self.variant.as_deref())
     }
 
     fn prefix(&self) -> Option<String> {
-        Some("auc:item".to_string())
+        Some(format!("auc:{}", self.market.namespace()))
     }
 }
 
-pub struct StorageActor;
+/// Persists auction data to whichever `PriceStore` it was given
+pub struct StorageActor {
+    store: Box<dyn PriceStore>,
+}
 
-impl Default for StorageActor {
-    fn default() -> Self {
-        Self {}
+impl StorageActor {
+    pub fn new(store: Box<dyn PriceStore>) -> Self {
+        Self { store }
     }
 }
 
@@ -35,11 +44,55 @@ impl Actor for StorageActor {
 #[derive(Debug, Message)]
 #[rtype(result = "StorageResult")]
 pub struct StoreAuction {
-    pub db_host: String,
     pub auction_row: AuctionRow,
     pub timestamp: i64,
 }
 
+/// Every auction seen in a snapshot, for storing the full order book
+#[derive(Debug, Message)]
+#[rtype(result = "StorageResult")]
+pub struct StoreSnapshot {
+    pub market: Market,
+    pub auctions: Vec<Auction>,
+    pub timestamp: i64,
+}
+
+/// The market statistics for every item in a snapshot, each stored as its own series
+#[derive(Debug, Message)]
+#[rtype(result = "StorageResult")]
+pub struct StoreStats {
+    pub stats: Vec<MarketStats>,
+    pub timestamp: i64,
+}
+
+/// The estimated sales for every item since the previous snapshot
+#[derive(Debug, Message)]
+#[rtype(result = "StorageResult")]
+pub struct StoreSales {
+    pub sales: Vec<SalesEstimate>,
+    pub timestamp: i64,
+}
+
+/// Item metadata, as fetched from the Game Data API
+#[derive(Debug, Message)]
+#[rtype(result = "StorageResult")]
+pub struct StoreItems(pub Vec<Item>);
+
+/// Which of the item ids have no metadata stored yet
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<u64>, Error>")]
+pub struct MissingItems(pub Vec<u64>);
+
+/// Battle pet species names, as fetched from the Game Data API
+#[derive(Debug, Message)]
+#[rtype(result = "StorageResult")]
+pub struct StorePets(pub Vec<PetSpecies>);
+
+/// Which of the battle pet species ids have no names stored yet
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<u32>, Error>")]
+pub struct MissingPets(pub Vec<u32>);
+
 #[derive(Debug, actix::MessageResponse)]
 pub enum StorageResult {
     Failed(String),
@@ -49,33 +102,136 @@ pub enum StorageResult {
 impl Handler<StoreAuction> for StorageActor {
     type Result = StorageResult;
 
-    fn handle(&mut self, msg: StoreAuction, ctx: &mut Self::Context) -> Self::Result {
-        match crate::db::redis_connect(msg.db_host) {
+    fn handle(&mut self, msg: StoreAuction, _: &mut Self::Context) -> Self::Result {
+        trace!("Storing: {:?}", msg.auction_row);
+        match self.store.store_auction(&msg.auction_row, msg.timestamp) {
+            Ok(_) => {
+                trace!("Stored {}", msg.auction_row.item_id);
+                StorageResult::Success
+            }
             Err(e) => {
-                error!("Failed to connect to redis: {}", e);
-                StorageResult::Failed(format!("Redis connection error: {}", e))
+                error!("Failed to store item {:?}: {:?}", msg.auction_row, e);
+                StorageResult::Failed(format!("Storage error: {:?}", e))
             }
-            Ok((_, mut con)) => {
-                trace!("Storing: {:?}", msg.auction_row);
-                match crate::db::store_auction(
-                    &mut con,
-                    msg.auction_row.to_key(),
-                    msg.timestamp,
-                    msg.auction_row.unit_price,
-                    msg.auction_row.auction_id.to_string(),
-                    msg.auction_row.item_id,
-                    msg.auction_row.quantity,
-                ) {
-                    Ok(_) => {
-                        trace!("Stored {}", msg.auction_row.item_id);
-                        StorageResult::Success
-                    }
-                    Err(e) => {
-                        error!("Failed to store item {:?}: {}", msg.auction_row, e);
-                        StorageResult::Failed(format!("Redis error: {}", e))
-                    }
-                }
+        }
+    }
+}
+
+impl Handler<StoreSnapshot> for StorageActor {
+    type Result = StorageResult;
+
+    fn handle(&mut self, msg: StoreSnapshot, _: &mut Self::Context) -> Self::Result {
+        trace!(
+            "Storing {} {:?} auctions at {}",
+            msg.auctions.len(),
+            msg.market,
+            msg.timestamp
+        );
+        match self
+            .store
+            .store_snapshot(msg.market, msg.timestamp, &msg.auctions)
+        {
+            Ok(_) => StorageResult::Success,
+            Err(e) => {
+                error!(
+                    "Failed to store {:?} snapshot {}: {:?}",
+                    msg.market, msg.timestamp, e
+                );
+                StorageResult::Failed(format!("Storage error: {:?}", e))
+            }
+        }
+    }
+}
+
+impl StorageActor {
+    /// Store each named value as a point in the `{key}:{name}` series
+    fn store_values(
+        &mut self,
+        key: String,
+        values: Vec<(&'static str, u64)>,
+        timestamp: i64,
+    ) -> Result<(), Error> {
+        for (name, value) in values {
+            let series = format!("{}:{}", key, name);
+            if let Err(e) = self.store.store_point(&series, timestamp, value) {
+                error!("Failed to store {}: {:?}", series, e);
+                return Err(e);
             }
         }
+        Ok(())
+    }
+}
+
+impl Handler<StoreStats> for StorageActor {
+    type Result = StorageResult;
+
+    fn handle(&mut self, msg: StoreStats, _: &mut Self::Context) -> Self::Result {
+        trace!("Storing stats for {} items", msg.stats.len());
+        for stats in msg.stats {
+            if let Err(e) = self.store_values(stats.to_key(), stats.values(), msg.timestamp) {
+                return StorageResult::Failed(format!("Storage error: {:?}", e));
+            }
+        }
+        StorageResult::Success
+    }
+}
+
+impl Handler<StoreSales> for StorageActor {
+    type Result = StorageResult;
+
+    fn handle(&mut self, msg: StoreSales, _: &mut Self::Context) -> Self::Result {
+        trace!("Storing sales for {} items", msg.sales.len());
+        for sales in msg.sales {
+            if let Err(e) = self.store_values(sales.to_key(), sales.values(), msg.timestamp) {
+                return StorageResult::Failed(format!("Storage error: {:?}", e));
+            }
+        }
+        StorageResult::Success
+    }
+}
+
+impl Handler<StoreItems> for StorageActor {
+    type Result = StorageResult;
+
+    fn handle(&mut self, msg: StoreItems, _: &mut Self::Context) -> Self::Result {
+        trace!("Storing metadata for {} items", msg.0.len());
+        match self.store.store_items(msg.0) {
+            Ok(_) => StorageResult::Success,
+            Err(e) => {
+                error!("Failed to store items: {:?}", e);
+                StorageResult::Failed(format!("Storage error: {:?}", e))
+            }
+        }
+    }
+}
+
+impl Handler<MissingItems> for StorageActor {
+    type Result = Result<Vec<u64>, Error>;
+
+    fn handle(&mut self, msg: MissingItems, _: &mut Self::Context) -> Self::Result {
+        self.store.missing_items(&msg.0)
+    }
+}
+
+impl Handler<StorePets> for StorageActor {
+    type Result = StorageResult;
+
+    fn handle(&mut self, msg: StorePets, _: &mut Self::Context) -> Self::Result {
+        trace!("Storing names for {} pet species", msg.0.len());
+        match self.store.store_pets(msg.0) {
+            Ok(_) => StorageResult::Success,
+            Err(e) => {
+                error!("Failed to store pet species: {:?}", e);
+                StorageResult::Failed(format!("Storage error: {:?}", e))
+            }
+        }
+    }
+}
+
+impl Handler<MissingPets> for StorageActor {
+    type Result = Result<Vec<u32>, Error>;
+
+    fn handle(&mut self, msg: MissingPets, _: &mut Self::Context) -> Self::Result {
+        self.store.missing_pets(&msg.0)
     }
 }
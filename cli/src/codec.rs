@@ -0,0 +1,166 @@
+//! The compression of archived snapshots. Archives written with any codec are told apart by
+//! their magic bytes, or failing that their extension, so they can be read side by side.
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The zstd compression level, 1-21; higher compresses smaller but slower. Snapshots are big, so
+/// higher levels eat into the sync interval for little gain.
+const ZSTD_LEVEL: i32 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Xz,
+    /// Optionally with a dictionary trained on auction JSON, e.g. by `zstd --train`
+    Zstd,
+    Gzip,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Zstd
+    }
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::Xz, Codec::Zstd, Codec::Gzip];
+
+    /// The extension of archives written with the codec
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Xz => "xz",
+            Codec::Zstd => "zst",
+            Codec::Gzip => "gz",
+        }
+    }
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Codec::Xz => &[0xFD, b'7', b'z', b'X', b'Z', 0x00],
+            Codec::Zstd => &[0x28, 0xB5, 0x2F, 0xFD],
+            Codec::Gzip => &[0x1F, 0x8B],
+        }
+    }
+
+    /// The codec an archive's extension is for, if any
+    pub fn for_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?;
+        Self::ALL.iter().copied().find(|c| ext == c.extension())
+    }
+
+    /// The codec an archive was written with, by its magic bytes or failing that its extension
+    pub fn detect(path: &Path) -> Result<Self, Error> {
+        let mut head = Vec::with_capacity(6);
+        File::open(path)?.take(6).read_to_end(&mut head)?;
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|c| head.starts_with(c.magic()))
+            .or_else(|| Self::for_path(path))
+            .ok_or_else(|| {
+                Error::CorruptArchive(format!("{} isn't a known archive", path.display()))
+            })
+    }
+
+    /// Decompress `file`, which needs the `dictionary` if it was compressed with one
+    pub fn reader(self, file: File, dictionary: Option<&[u8]>) -> Result<Box<dyn Read>, Error> {
+        Ok(match self {
+            Codec::Xz => Box::new(lzma::LzmaReader::new_decompressor(file)?),
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::with_dictionary(
+                BufReader::new(file),
+                dictionary.unwrap_or_default(),
+            )?),
+            Codec::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        })
+    }
+
+    /// Compress into `file`, with the `dictionary` if it's zstd
+    pub fn writer(self, file: File, dictionary: Option<&[u8]>) -> Result<Encoder, Error> {
+        let file = BufWriter::new(file);
+        Ok(match self {
+            Codec::Xz => Encoder::Xz(lzma::LzmaWriter::new_compressor(file, 9)?),
+            Codec::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::with_dictionary(
+                file,
+                ZSTD_LEVEL,
+                dictionary.unwrap_or_default(),
+            )?),
+            Codec::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+        })
+    }
+}
+
+/// Compresses an archive as it's written
+pub enum Encoder {
+    Xz(lzma::LzmaWriter<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<BufWriter<File>>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+}
+
+impl Encoder {
+    /// Write out the end of the compressed stream, and flush it
+    pub fn finish(self) -> Result<(), Error> {
+        let mut file = match self {
+            Encoder::Xz(w) => w.finish()?,
+            Encoder::Zstd(w) => w.finish()?,
+            Encoder::Gzip(w) => w.finish()?,
+        };
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Xz(w) => w.write(buf),
+            Encoder::Zstd(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Xz(w) => w.flush(),
+            Encoder::Zstd(w) => w.flush(),
+            Encoder::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_detects_each_codec() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = br#"{"auctions":[{"id":1,"item":{"id":109119},"unit_price":100}]}"#;
+        for codec in Codec::ALL.iter().copied() {
+            let dictionary = Some(&json[..20]).filter(|_| codec == Codec::Zstd);
+            // Named for none of them, so only the magic bytes give it away
+            let path = dir.path().join(format!("snap-{:?}.bin", codec));
+            let mut w = codec
+                .writer(File::create(&path).unwrap(), dictionary)
+                .unwrap();
+            w.write_all(json).unwrap();
+            w.finish().unwrap();
+
+            assert_eq!(Codec::detect(&path).unwrap(), codec);
+            let mut read = vec![];
+            codec
+                .reader(File::open(&path).unwrap(), dictionary)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(&read[..], &json[..]);
+        }
+        assert_eq!(Codec::for_path("a.zst".as_ref()), Some(Codec::Zstd));
+        assert_eq!(Codec::for_path("a.json".as_ref()), None);
+    }
+}
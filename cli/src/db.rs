@@ -1,269 +1,720 @@
-use crate::{realm::Auction, realm::Item, AsKey};
+use crate::{
+    actors::AuctionRow, realm::Auction, realm::Item, realm::Market, realm::PetSpecies,
+    realm::DEFAULT_LOCALE, AsKey, Error, Settings,
+};
 use log::{error, info, trace};
-use redis::Connection;
 use redis::{Client, RedisError};
+use redis::{Commands, Connection};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+pub mod memory;
+pub mod sqlite;
+
+/// The flag set once the watchlist has been seeded from `ref-data/init.json`
+const WATCHLIST_SEEDED: &str = "watchlist_seeded";
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct InitRefData {
     watchlist: Vec<u64>,
 }
 
+/// A backend that persists auction prices alongside the item reference data needed to query them
+pub trait PriceStore: Send {
+    /// Record the given auction row as seen at `ts` (seconds since the epoch)
+    fn store_auction(&mut self, row: &AuctionRow, ts: i64) -> Result<(), Error>;
+
+    /// Record a single value for the series `key` at `ts`
+    fn store_point(&mut self, key: &str, ts: i64, value: u64) -> Result<(), Error>;
+
+    /// Record every auction in the market's snapshot taken at `ts`, i.e. the full order book
+    fn store_snapshot(
+        &mut self,
+        market: Market,
+        ts: i64,
+        auctions: &[Auction],
+    ) -> Result<(), Error>;
+
+    /// The market's full order book recorded at `ts`, empty if none was stored
+    fn get_snapshot(&mut self, market: Market, ts: i64) -> Result<Vec<Auction>, Error>;
+
+    /// All `(timestamp, unit price)` points for the series `key`, oldest first
+    fn get_range(&mut self, key: &str) -> Result<Vec<(i64, u64)>, Error>;
+
+    /// Re-key every series whose key starts with `from` to start with `to` instead
+    fn rename_series(&mut self, from: &str, to: &str) -> Result<(), Error>;
+
+    /// Store the items' metadata and index them by name, returning the number stored
+    fn store_items(&mut self, items: Vec<Item>) -> Result<usize, Error>;
+
+    /// Find an item's metadata by its item id
+    fn get_item_metadata(&mut self, id: u64) -> Result<Option<Item>, Error>;
+
+    /// Those of the item ids with no metadata stored
+    fn missing_items(&mut self, ids: &[u64]) -> Result<Vec<u64>, Error> {
+        let mut missing = vec![];
+        for id in ids {
+            if self.get_item_metadata(*id)?.is_none() {
+                missing.push(*id);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Store the battle pet species' names, returning the number stored
+    fn store_pets(&mut self, pets: Vec<PetSpecies>) -> Result<usize, Error>;
+
+    /// Find a battle pet species by its id
+    fn get_pet(&mut self, species_id: u32) -> Result<Option<PetSpecies>, Error>;
+
+    /// Those of the species ids with no names stored
+    fn missing_pets(&mut self, species_ids: &[u32]) -> Result<Vec<u32>, Error> {
+        let mut missing = vec![];
+        for id in species_ids {
+            if self.get_pet(*id)?.is_none() {
+                missing.push(*id);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// List the ids for the given item name in `locale`, e.g. `de_DE`
+    fn get_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error>;
+
+    /// List the ids of every item whose name in `locale` starts with `name`
+    fn search_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error>;
+
+    /// Add the item ids to the watchlist, returning how many weren't already on it
+    fn add_to_watchlist(&mut self, ids: &[u64]) -> Result<u64, Error>;
+
+    /// List the item ids on the watchlist
+    fn get_watchlist(&mut self) -> Result<Vec<u64>, Error>;
+
+    /// Take an item off the watchlist, returning whether it was on it
+    fn remove_from_watchlist(&mut self, id: u64) -> Result<bool, Error>;
+
+    /// Whether the named flag has been set, e.g. once the watchlist has been seeded
+    fn has_flag(&mut self, name: &str) -> Result<bool, Error>;
+
+    /// Set the named flag, for good
+    fn set_flag(&mut self, name: &str) -> Result<(), Error>;
+
+    /// The variant keys auctions of the item have been stored under, e.g. `b1487.6646`
+    fn get_variants(&mut self, item_id: u64) -> Result<Vec<String>, Error>;
+
+    /// Find an item's metadata by its name in `locale`
+    fn get_item_metadata_by_name(
+        &mut self,
+        locale: &str,
+        name: &str,
+    ) -> Result<Option<Item>, Error> {
+        match self.get_ids_for_item(locale, name)?.into_iter().next() {
+            Some(id) => {
+                trace!("Found id {}: for item {}", id, name);
+                self.get_item_metadata(id)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The available `PriceStore` implementations
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// RedisTimeSeries at `db_host`
+    Redis,
+    /// An embedded database file at `sqlite_path`
+    Sqlite,
+    /// Held in this process only and discarded on exit
+    Memory,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Redis
+    }
+}
+
+/// Open the storage backend described by the settings, moving any series keyed before markets
+/// had namespaces into them
+pub fn open_store(settings: &Settings) -> Result<Box<dyn PriceStore>, Error> {
+    let mut store: Box<dyn PriceStore> = match settings.backend {
+        Backend::Redis => Box::new(RedisStore::connect(settings.db_host.clone())?),
+        Backend::Sqlite => Box::new(sqlite::SqliteStore::open(&settings.sqlite_path())?),
+        Backend::Memory => Box::new(memory::MemoryStore::default()),
+    };
+    crate::migrate::migrate_series(store.as_mut(), settings.realm_id)?;
+    Ok(store)
+}
+
+/// Names the storage backend described by the settings, e.g. `sqlite:data/waw.sqlite`, to tell
+/// what's been loaded into which
+pub fn store_id(settings: &Settings) -> String {
+    match settings.backend {
+        Backend::Redis => format!("redis://{}", settings.db_host),
+        Backend::Sqlite => format!("sqlite:{}", settings.sqlite_path()),
+        Backend::Memory => "memory".to_string(),
+    }
+}
+
 pub fn redis_connect(db_host: String) -> Result<(Client, Connection), RedisError> {
     let client: Client = Client::open(format!("redis://{}/", db_host)).unwrap();
     let con = client.get_connection()?;
     Ok((client, con))
 }
 
-pub async fn dump_redis_proto(auc: &Auction, ts: i64) -> Result<(), String> {
-    let mut opt = String::new();
-    let key = format!("auc:{}", &auc.item.to_key());
-    let auc_id = &auc.item.id.to_string();
-    let item_id = &auc.item.id.to_string();
-    let quant = &auc.quantity.to_string();
-    let val = &auc.unit_price.unwrap().to_string();
-    let tss = &ts.to_string();
+/// The `TS.ADD` that `RedisStore::store_auction` sends for the row, as a Redis protocol message
+/// for `redis-cli --pipe`
+pub fn dump_redis_proto(row: &AuctionRow, ts: i64) -> String {
     let cmd = vec![
-        "TS.ADD",
-        &key,
-        tss,
-        val,
-        "labels",
-        "auction_id",
-        auc_id,
-        "item",
-        item_id,
-        "quantity",
-        quant,
+        "TS.ADD".to_string(),
+        row.to_key(),
+        ts.to_string(),
+        row.unit_price.to_string(),
+        "RETENTION".to_string(),
+        "9999999999".to_string(),
+        "ON_DUPLICATE".to_string(),
+        "LAST".to_string(),
+        "LABELS".to_string(),
+        "auction_id".to_string(),
+        row.auction_id.to_string(),
+        "item".to_string(),
+        row.item_id.to_string(),
+        "quantity".to_string(),
+        row.quantity.to_string(),
     ];
-    opt.push_str(&format!("*{}\r\n", cmd.len()));
+    let mut opt = format!("*{}\r\n", cmd.len());
     for arg in cmd {
         opt.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
     }
-    println!("{}", opt);
-    Ok(())
+    opt
 }
 
-/// `TS.RANGE` for the given key
-pub fn get_range<T>(
-    con: &mut Connection,
-    item_md: &T,
-) -> std::result::Result<redis::Value, redis::RedisError>
-where
-    T: AsKey,
-{
-    info!("Get range for {}", item_md.to_key());
-    redis::cmd("TS.RANGE")
-    //FIXME this is fucked; encapsulate store & lookup in item obj?
-        .arg(format!("auc:{}", item_md.to_key()))
-        .arg("-".to_string())
-        .arg("+".to_string())
-        .query::<redis::Value>(con)
+/// Lower-case the name and replace everything but letters and digits, in any script, with `_`
+fn sanitise_name(name: String) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
 }
 
-fn sanitise_name(name: String) -> String {
-    lazy_static::lazy_static! {
-        static ref PUNCT_RE: regex::Regex = regex::Regex::new(r"[[:punct:]]").unwrap();
+/// The key of the Redis sorted set holding the ids of items with the name in `locale`.
+/// English names keep the original `ids:item:{name}` keys.
+fn name_key(locale: &str, name: &str) -> String {
+    if locale == DEFAULT_LOCALE {
+        format!("ids:item:{}", sanitise_name(name.to_string()))
+    } else {
+        format!("ids:{}:item:{}", locale, sanitise_name(name.to_string()))
     }
-    format!("{}", PUNCT_RE.replace_all(&name.to_ascii_lowercase(), "_")).replace(' ', "_")
 }
 
-/// Load the watchlist from the given file and store it
-pub fn store_watchlist(
-    con: &mut redis::Connection,
-    path: &'static str,
-) -> Result<u64, redis::RedisError> {
-    let init = std::fs::read_to_string(path).expect("Unable to read file");
-    let res: InitRefData = serde_json::from_str(&init).expect("Unable to parse");
-    let mut cmd = redis::cmd("SADD");
-    res.watchlist
-        .into_iter()
-        .fold(cmd.arg("watchlist"), |c, id| { 
-            trace!("Store watchlist {} {}", String::from_utf8(c.get_packed_command()).unwrap(), id); 
-            c.arg(id.to_string()) 
-        })
-        .query::<u64>(con)
+/// An item's metadata as the fields of its `ref:item:{id}` hash, with a `name:{locale}` field
+/// per localised name
+fn item_fields(i: &Item) -> Vec<(String, String)> {
+    let mut fields = vec![
+        ("id".to_string(), i.id.to_string()),
+        ("en_us".to_string(), i.en_us.clone()),
+    ];
+    let optional = vec![
+        ("quality", i.quality.clone()),
+        ("class_id", i.class_id.map(|v| v.to_string())),
+        ("subclass_id", i.subclass_id.map(|v| v.to_string())),
+        ("level", i.level.map(|v| v.to_string())),
+        ("sell_price", i.sell_price.map(|v| v.to_string())),
+        ("stackable", i.stackable.map(|v| v.to_string())),
+    ];
+    fields.extend(
+        optional
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v?))),
+    );
+    fields.extend(
+        i.names
+            .iter()
+            .map(|(locale, name)| (format!("name:{}", locale), name.clone())),
+    );
+    fields
 }
 
-pub fn store_item_metadata(
-    con: &mut Connection,
-    path: &'static str,
-) -> anyhow::Result<Vec<String>> {
+/// The item held in the fields of a `ref:item:{id}` hash
+fn item_from_fields(m: HashMap<String, String>) -> Option<Item> {
+    fn parse<T: std::str::FromStr>(m: &HashMap<String, String>, k: &str) -> Option<T> {
+        m.get(k)?.parse().ok()
+    }
+    Some(Item {
+        id: parse(&m, "id")?,
+        en_us: m.get("en_us")?.clone(),
+        names: m
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix("name:")?.to_string(), v.clone())))
+            .collect(),
+        quality: m.get("quality").cloned(),
+        class_id: parse(&m, "class_id"),
+        subclass_id: parse(&m, "subclass_id"),
+        level: parse(&m, "level"),
+        sell_price: parse(&m, "sell_price"),
+        stackable: parse(&m, "stackable"),
+    })
+}
+
+/// Seed the watchlist from the given file, unless it's been seeded before, so that items taken
+/// off it stay off. Returns how many were added.
+pub fn store_watchlist(store: &mut dyn PriceStore, path: &str) -> Result<u64, Error> {
+    let init = std::fs::read_to_string(path)?;
+    let res: InitRefData = serde_json::from_str(&init)?;
+    if store.has_flag(WATCHLIST_SEEDED)? {
+        info!("Watchlist already seeded");
+        return Ok(0);
+    }
+    let added = store.add_to_watchlist(&res.watchlist)?;
+    store.set_flag(WATCHLIST_SEEDED)?;
+    Ok(added)
+}
+
+/// Load the item metadata from the given CSV file and store it
+pub fn store_item_metadata(store: &mut dyn PriceStore, path: &str) -> Result<usize, Error> {
     let mut reader = csv::Reader::from_path(std::path::Path::new(path))?;
-    reader
-        .deserialize::<crate::realm::Item>()
-        .fold(redis::pipe().atomic(), |p, item| match item {
-            Ok(i) => {
-                let ids_key = format!("ids:item:{}", sanitise_name(i.en_us.clone()));
-                p.hset(format!("ref:{}", i.to_key()), "id", i.id)
-                    .arg("en_us")
-                    .arg(i.en_us.clone())
-                    .ignore()
-                    .zadd(ids_key, i.id, 0)
-                    .ignore()
-                    .set(format!("names:item:{}", i.id), i.en_us.clone())
-                    .ignore()
-            }
+    let items = reader
+        .deserialize::<Item>()
+        .filter_map(|item| match item {
+            Ok(i) => Some(i),
             Err(e) => {
                 error!("Failed to parse item CSV: {}", e);
-                p
+                None
             }
         })
-        .execute(con);
-    let x = redis::pipe().keys("ids:item:*").query(con)?;
-    Ok(x)
+        .collect();
+    store.store_items(items)
 }
 
-/// Find an item's metadata by its item id
-pub fn get_item_metadata(
-    con: &mut Connection,
-    id: u64,
-) -> Result<Option<crate::realm::Item>, redis::RedisError> {
-    Ok(redis::pipe()
-        .hgetall(format!("ref:item:{}", id))
-        .query::<Vec<HashMap<String, String>>>(con)?
-        .into_iter()
-        .take(1)
-        .map(|m| Item {
-            id: m.get("id").unwrap().parse().unwrap(),
-            en_us: m.get("en_us").unwrap().clone(),
-        })
-        .next())
+/// Prices stored as RedisTimeSeries keys, with item metadata in hashes and sorted sets
+pub struct RedisStore {
+    con: Connection,
 }
 
-pub fn get_item_metadata_by_name(
-    con: &mut Connection,
-    name: String,
-) -> Result<Option<crate::realm::Item>, redis::RedisError> {
-    match get_ids_for_item(con, name.clone())
-        .expect("Failed looking up item metadata by name")
-        .into_iter()
-        .take(1)
-        .next()
-    {
-        Some(id) => {
-            trace!("Found id {}: for item {}", id, name);
-            Ok(get_item_metadata(con, id.parse().unwrap())?)
-        }
-        None => Ok(None),
+impl RedisStore {
+    pub fn connect(db_host: String) -> Result<Self, Error> {
+        let (_, con) = redis_connect(db_host)?;
+        Ok(Self { con })
     }
-}
 
-/// List the id for the given name
-pub fn get_ids_for_item(con: &mut Connection, name: String) -> anyhow::Result<Vec<String>> {
-    let key = format!("ids:item:{}", sanitise_name(name.clone()));
-    info!("Id lookup key {}", key);
-    Ok(redis::pipe()
-        .zrevrange(key, -1, -1)
-        .query::<Vec<Vec<String>>>(con)?
-        .into_iter()
-        .flatten()
-        .collect())
+    /// The ids stored under a name index key, in order
+    fn ids_at(&mut self, key: String) -> Result<Vec<u64>, Error> {
+        let mut ids: Vec<u64> = redis::pipe()
+            .zrange(key, 0, -1)
+            .query::<Vec<Vec<String>>>(&mut self.con)?
+            .into_iter()
+            .flatten()
+            .filter_map(|id| id.parse().ok())
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
 }
 
-pub fn search_ids_for_item(con: &mut Connection, name: String) -> anyhow::Result<Vec<Vec<String>>> {
-    let search_term = format!("ids:item:{}*", sanitise_name(name.clone()));
-    info!("Item id search by key {}", search_term);
-    let keys = redis::cmd("keys")
-        .arg(search_term.clone())
-        .query::<Vec<String>>(con)?;
-    info!("Item id search results: {:?}", keys);
-    Ok(keys
-        .into_iter()
-        .filter_map(|key| {
-            trace!("Found key for item {} search: {}", search_term, key);
-            match redis::pipe()
-                .zrevrange(key, -1, -1)
-                .query::<Vec<Vec<String>>>(con)
-            {
-                Ok(v) => Some(v.into_iter().flatten().collect()),
-                Err(_) => None,
+impl PriceStore for RedisStore {
+    fn store_auction(&mut self, row: &AuctionRow, ts: i64) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        if let Some(variant) = &row.variant {
+            pipe.sadd(format!("variants:item:{}", row.item_id), variant)
+                .ignore();
+        }
+        pipe.atomic()
+            .cmd("TS.ADD")
+            .arg(row.to_key())
+            .arg(ts.to_string())
+            .arg(row.unit_price.to_string())
+            .arg("RETENTION")
+            .arg("9999999999")
+            // Replaying a snapshot overwrites its points rather than failing
+            .arg("ON_DUPLICATE")
+            .arg("LAST")
+            .arg("LABELS")
+            .arg("auction_id")
+            .arg(row.auction_id.to_string())
+            .arg("item")
+            .arg(row.item_id.to_string())
+            .arg("quantity")
+            .arg(row.quantity.to_string())
+            .query::<()>(&mut self.con)?;
+        Ok(())
+    }
+
+    fn store_point(&mut self, key: &str, ts: i64, value: u64) -> Result<(), Error> {
+        redis::cmd("TS.ADD")
+            .arg(key)
+            .arg(ts.to_string())
+            .arg(value.to_string())
+            .arg("RETENTION")
+            .arg("9999999999")
+            .arg("ON_DUPLICATE")
+            .arg("LAST")
+            .query::<()>(&mut self.con)?;
+        Ok(())
+    }
+
+    /// Each auction is a JSON field, by auction id, in the `book:{namespace}:{ts}` hash
+    fn store_snapshot(
+        &mut self,
+        market: Market,
+        ts: i64,
+        auctions: &[Auction],
+    ) -> Result<(), Error> {
+        let key = format!("book:{}:{}", market.namespace(), ts);
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        for chunk in auctions.chunks(1000) {
+            let fields = chunk
+                .iter()
+                .map(|a| Ok((a.id, serde_json::to_string(a)?)))
+                .collect::<Result<Vec<(u64, String)>, Error>>()?;
+            pipe.hset_multiple(&key, &fields).ignore();
+        }
+        pipe.zadd(format!("books:{}", market.namespace()), ts, ts)
+            .ignore()
+            .query::<()>(&mut self.con)?;
+        Ok(())
+    }
+
+    fn get_snapshot(&mut self, market: Market, ts: i64) -> Result<Vec<Auction>, Error> {
+        let mut auctions = redis::cmd("HVALS")
+            .arg(format!("book:{}:{}", market.namespace(), ts))
+            .query::<Vec<String>>(&mut self.con)?
+            .iter()
+            .map(|a| serde_json::from_str(a))
+            .collect::<Result<Vec<Auction>, _>>()?;
+        auctions.sort_by_key(|a| a.id);
+        Ok(auctions)
+    }
+
+    /// `TS.RANGE` for the given key
+    fn get_range(&mut self, key: &str) -> Result<Vec<(i64, u64)>, Error> {
+        info!("Get range for {}", key);
+        Ok(redis::cmd("TS.RANGE")
+            .arg(key)
+            .arg("-")
+            .arg("+")
+            .query::<Vec<(i64, u64)>>(&mut self.con)?)
+    }
+
+    fn rename_series(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let keys: Vec<String> = self.con.scan_match(format!("{}*", from))?.collect();
+        for key in keys.iter() {
+            redis::cmd("RENAME")
+                .arg(key)
+                .arg(format!("{}{}", to, &key[from.len()..]))
+                .query::<()>(&mut self.con)?;
+        }
+        info!("Renamed {} {}* keys to {}*", keys.len(), from, to);
+        Ok(())
+    }
+
+    fn store_items(&mut self, items: Vec<Item>) -> Result<usize, Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for i in items.iter() {
+            pipe.hset_multiple(format!("ref:{}", i.to_key()), &item_fields(i))
+                .ignore()
+                .set(format!("names:item:{}", i.id), i.en_us.clone())
+                .ignore();
+            for (locale, name) in i.localised_names() {
+                pipe.zadd(name_key(locale, name), i.id, 0).ignore();
             }
-        })
-        .collect())
-}
+        }
+        pipe.query::<()>(&mut self.con)?;
+        Ok(items.len())
+    }
+
+    fn get_item_metadata(&mut self, id: u64) -> Result<Option<Item>, Error> {
+        Ok(redis::pipe()
+            .hgetall(format!("ref:item:{}", id))
+            .query::<Vec<HashMap<String, String>>>(&mut self.con)?
+            .into_iter()
+            .take(1)
+            .filter_map(item_from_fields)
+            .next())
+    }
 
-pub fn store_auction(
-    con: &mut Connection,
-    key: String,
-    ts: i64,
-    unit_price: u64,
-    auc_id: String,
-    item_id: u64,
-    quantity: u16,
-) -> Result<(), redis::RedisError> {
-    redis::pipe()
-        .atomic()
-        .cmd("TS.ADD")
-        .arg(key)
-        .arg(ts.to_string())
-        .arg(unit_price.to_string())
-        .arg("RETENTION")
-        .arg("9999999999")
-        .arg("LABELS")
-        .arg("auction_id")
-        .arg(auc_id)
-        .arg("item")
-        .arg(item_id.to_string())
-        .arg("quantity")
-        .arg(quantity.to_string())
-        .execute(con);
-
-    Ok(())
+    fn missing_items(&mut self, ids: &[u64]) -> Result<Vec<u64>, Error> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let exists: Vec<bool> = ids
+            .iter()
+            .fold(&mut redis::pipe(), |p, id| {
+                p.exists(format!("ref:item:{}", id))
+            })
+            .query(&mut self.con)?;
+        Ok(ids
+            .iter()
+            .zip(exists)
+            .filter(|(_, e)| !e)
+            .map(|(id, _)| *id)
+            .collect())
+    }
+
+    /// Each species is a `ref:pet:{id}` hash of `en_us` and a `name:{locale}` field per locale
+    fn store_pets(&mut self, pets: Vec<PetSpecies>) -> Result<usize, Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for p in pets.iter() {
+            let mut fields = vec![("en_us".to_string(), p.en_us.clone())];
+            fields.extend(
+                p.names
+                    .iter()
+                    .map(|(locale, name)| (format!("name:{}", locale), name.clone())),
+            );
+            pipe.hset_multiple(format!("ref:pet:{}", p.id), &fields)
+                .ignore();
+        }
+        pipe.query::<()>(&mut self.con)?;
+        Ok(pets.len())
+    }
+
+    fn get_pet(&mut self, species_id: u32) -> Result<Option<PetSpecies>, Error> {
+        let m: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(format!("ref:pet:{}", species_id))
+            .query(&mut self.con)?;
+        Ok(m.get("en_us").map(|en_us| PetSpecies {
+            id: species_id,
+            en_us: en_us.clone(),
+            names: m
+                .iter()
+                .filter_map(|(k, v)| Some((k.strip_prefix("name:")?.to_string(), v.clone())))
+                .collect(),
+        }))
+    }
+
+    fn get_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        let key = name_key(locale, name);
+        info!("Id lookup key {}", key);
+        self.ids_at(key)
+    }
+
+    fn search_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        let search_term = format!("{}*", name_key(locale, name));
+        info!("Item id search by key {}", search_term);
+        let keys = redis::cmd("keys")
+            .arg(search_term.clone())
+            .query::<Vec<String>>(&mut self.con)?;
+        info!("Item id search results: {:?}", keys);
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                trace!("Found key for item {} search: {}", search_term, key);
+                self.ids_at(key).ok()
+            })
+            .flatten()
+            .collect())
+    }
+
+    fn add_to_watchlist(&mut self, ids: &[u64]) -> Result<u64, Error> {
+        let mut cmd = redis::cmd("SADD");
+        Ok(ids
+            .iter()
+            .fold(cmd.arg("watchlist"), |c, id| {
+                trace!("Store watchlist {}", id);
+                c.arg(id.to_string())
+            })
+            .query::<u64>(&mut self.con)?)
+    }
+
+    fn get_watchlist(&mut self) -> Result<Vec<u64>, Error> {
+        Ok(redis::cmd("SMEMBERS")
+            .arg("watchlist")
+            .query(&mut self.con)?)
+    }
+
+    fn remove_from_watchlist(&mut self, id: u64) -> Result<bool, Error> {
+        Ok(redis::cmd("SREM")
+            .arg("watchlist")
+            .arg(id.to_string())
+            .query::<u64>(&mut self.con)?
+            > 0)
+    }
+
+    fn has_flag(&mut self, name: &str) -> Result<bool, Error> {
+        Ok(redis::cmd("SISMEMBER")
+            .arg("flags")
+            .arg(name)
+            .query(&mut self.con)?)
+    }
+
+    fn set_flag(&mut self, name: &str) -> Result<(), Error> {
+        redis::cmd("SADD")
+            .arg("flags")
+            .arg(name)
+            .query::<u64>(&mut self.con)?;
+        Ok(())
+    }
+
+    fn get_variants(&mut self, item_id: u64) -> Result<Vec<String>, Error> {
+        let mut variants: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(format!("variants:item:{}", item_id))
+            .query(&mut self.con)?;
+        variants.sort();
+        Ok(variants)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::PriceStore;
+    use crate::realm::{Auction, AuctionTime, Item, ItemIden, Market, Region, Target};
 
-    #[test]
-    fn get_items() -> Result<(), String> {
-        env_logger::init_from_env(
-            env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
-        );
-        let settings = crate::Settings::from("../Settings.toml").expect("Couldn't load settings");
-        let (_, mut con) =
-            crate::db::redis_connect(settings.db_host).expect("Couldn't connect to redis");
-        crate::db::store_watchlist(&mut con, "../ref-data/init.json")
-            .expect("Couldn't store watchlist");
-        crate::db::store_item_metadata(&mut con, "../ref-data/items.csv").unwrap();
-
-        // Make sure we loaded stuff
-        let ids: Vec<String> = redis::cmd("KEYS")
-            .arg("ids:item:*")
-            .query(&mut con)
+    fn item(id: u64, name: &str) -> Item {
+        Item {
+            id,
+            en_us: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Items sharing a name are all found by it, whichever the backend
+    fn finds_every_item_by_name(store: &mut dyn PriceStore) {
+        store
+            .store_items(vec![
+                item(109119, "True Iron Ore"),
+                item(72094, "Black Trillium Ore"),
+                item(72093, "Black Trillium Ore"),
+            ])
             .unwrap();
-        assert!(ids.len() > 0);
+        assert_eq!(
+            store
+                .get_ids_for_item("en_US", "Black Trillium Ore")
+                .unwrap(),
+            vec![72093, 72094]
+        );
+        assert_eq!(
+            store.get_ids_for_item("en_US", "True Iron Ore").unwrap(),
+            vec![109119]
+        );
+    }
 
-        let names: Vec<String> = redis::cmd("KEYS")
-            .arg("names:item:*")
-            .query(&mut con)
+    #[test]
+    fn finds_every_item_by_name_in_each_backend() {
+        finds_every_item_by_name(&mut crate::db::memory::MemoryStore::default());
+        finds_every_item_by_name(&mut crate::db::sqlite::SqliteStore::open(":memory:").unwrap());
+        // Needs a server, e.g. from redis.sh, so only run when one's given
+        if let Ok(host) = std::env::var("WAW_TEST_REDIS_HOST") {
+            finds_every_item_by_name(&mut super::RedisStore::connect(host).unwrap());
+        }
+    }
+
+    /// Order books of markets snapshotted at the same time don't overwrite each other
+    fn keeps_order_books_apart(store: &mut dyn PriceStore) {
+        let draenor = Market::Realm(Target {
+            region: Region::Eu,
+            realm_id: 1403,
+        });
+        let commodities = Market::Commodities(Region::Eu);
+        let book = |id, item_id| {
+            vec![Auction {
+                id,
+                item: ItemIden {
+                    id: item_id,
+                    ..Default::default()
+                },
+                buyout: None,
+                unit_price: Some(100),
+                quantity: 20,
+                time_left: AuctionTime::LONG,
+            }]
+        };
+        store.store_snapshot(draenor, 1, &book(1, 109119)).unwrap();
+        store
+            .store_snapshot(commodities, 1, &book(2, 2589))
             .unwrap();
-        assert!(names.len() > 0);
+        assert_eq!(store.get_snapshot(draenor, 1).unwrap(), book(1, 109119));
+        assert_eq!(store.get_snapshot(commodities, 1).unwrap(), book(2, 2589));
+        assert!(store
+            .get_snapshot(Market::Commodities(Region::Us), 1)
+            .unwrap()
+            .is_empty());
+    }
 
-        log::info!(
-            "ID LOOKUP {:?}",
-            redis::pipe()
-                .zrevrange("ids:item:true_iron_ore", -1, -1,)
-                .query::<redis::Value>(&mut con)
+    #[test]
+    fn keeps_order_books_apart_in_each_backend() {
+        keeps_order_books_apart(&mut crate::db::memory::MemoryStore::default());
+        keeps_order_books_apart(&mut crate::db::sqlite::SqliteStore::open(":memory:").unwrap());
+        if let Ok(host) = std::env::var("WAW_TEST_REDIS_HOST") {
+            keeps_order_books_apart(&mut super::RedisStore::connect(host).unwrap());
+        }
+    }
+
+    #[test]
+    fn get_items() -> Result<(), String> {
+        let mut store = crate::db::memory::MemoryStore::default();
+        assert_eq!(
+            crate::db::store_watchlist(&mut store, "../ref-data/init.json")
+                .expect("Couldn't store watchlist"),
+            10
+        );
+        assert!(store.remove_from_watchlist(72092).unwrap());
+        assert_eq!(
+            crate::db::store_watchlist(&mut store, "../ref-data/init.json").unwrap(),
+            0
+        );
+        assert!(!store.get_watchlist().unwrap().contains(&72092));
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("items.csv");
+        std::fs::write(
+            &csv,
+            "id,en_us\n109119,True Iron Ore\n109118,Blackrock Ore\n72092,Ghost Iron Ore\n\
+             72094,Black Trillium Ore\n2589,Bloodhide\n",
+        )
+        .unwrap();
+        assert_eq!(
+            crate::db::store_item_metadata(&mut store, csv.to_str().unwrap()).unwrap(),
+            5
         );
 
-        let item_ids = crate::db::get_ids_for_item(&mut con, "True Iron Ore".to_string()).unwrap();
+        let item_ids = store.get_ids_for_item("en_US", "True Iron Ore").unwrap();
         assert_eq!(item_ids.len(), 1);
 
-        let x =
-            crate::db::get_item_metadata_by_name(&mut con, "True Iron Ore".to_string()).unwrap();
+        let x = store
+            .get_item_metadata_by_name("en_US", "True Iron Ore")
+            .unwrap();
         assert!(x.is_some());
+        assert_eq!(x.unwrap(), item(109119, "True Iron Ore"));
+
+        // By id, not by name
+        let item_ids_res = store.search_ids_for_item("en_US", "b").unwrap();
+        assert_eq!(item_ids_res, vec![2589, 72094, 109118]);
+
+        assert!(store
+            .search_ids_for_item("en_US", "iron")
+            .unwrap()
+            .is_empty());
+
+        store
+            .store_items(vec![Item {
+                names: vec![("de_DE", "Haustierkäfig"), ("fr_FR", "Cage de mascotte")]
+                    .into_iter()
+                    .map(|(l, n)| (l.to_string(), n.to_string()))
+                    .collect(),
+                ..item(82800, "Pet Cage")
+            }])
+            .unwrap();
         assert_eq!(
-            x.unwrap(),
-            crate::realm::Item {
-                id: 109119,
-                en_us: "True Iron Ore".to_string()
-            }
+            store.search_ids_for_item("de_DE", "HAUSTIERKÄ").unwrap(),
+            vec![82800]
         );
-
-        let item_ids_res = crate::db::search_ids_for_item(&mut con, "true".to_string()).unwrap();
-        assert_eq!(item_ids_res.len(), 49);
+        assert_eq!(
+            store.get_ids_for_item("fr_FR", "Cage de mascotte").unwrap(),
+            vec![82800]
+        );
+        assert_eq!(
+            store.get_ids_for_item("en_US", "Pet Cage").unwrap(),
+            vec![82800]
+        );
+        assert!(store.search_ids_for_item("de_DE", "b").unwrap().is_empty());
+        assert_eq!(
+            super::sanitise_name("Minerai d'ectofer".to_string()),
+            "minerai_d_ectofer"
+        );
+        assert!(store.get_watchlist().unwrap().contains(&109119));
 
         Ok(())
     }
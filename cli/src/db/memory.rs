@@ -0,0 +1,190 @@
+use super::{sanitise_name, PriceStore};
+use crate::{
+    actors::AuctionRow,
+    realm::{Auction, Item, Market, PetSpecies},
+    AsKey, Error,
+};
+use log::trace;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Inner {
+    /// Points per series key, ordered by timestamp
+    series: HashMap<String, BTreeMap<i64, u64>>,
+    /// Every auction per market and snapshot timestamp
+    books: HashMap<(Market, i64), Vec<Auction>>,
+    items: HashMap<u64, Item>,
+    pets: HashMap<u32, PetSpecies>,
+    /// Item ids per locale and sanitised name
+    names: BTreeMap<(String, String), BTreeSet<u64>>,
+    watchlist: BTreeSet<u64>,
+    flags: HashSet<String>,
+    /// Variant keys seen per item id
+    variants: HashMap<u64, BTreeSet<String>>,
+}
+
+/// A store held entirely in memory and lost on exit. Clones share the same data.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MemoryStore {
+    fn with<T>(&self, f: impl FnOnce(&mut Inner) -> T) -> T {
+        f(&mut self.inner.lock().expect("Memory store lock poisoned"))
+    }
+}
+
+impl PriceStore for MemoryStore {
+    fn store_auction(&mut self, row: &AuctionRow, ts: i64) -> Result<(), Error> {
+        trace!("Storing {} at {}", row.to_key(), ts);
+        self.with(|i| {
+            if let Some(variant) = &row.variant {
+                i.variants
+                    .entry(row.item_id)
+                    .or_default()
+                    .insert(variant.clone());
+            }
+            i.series
+                .entry(row.to_key())
+                .or_default()
+                .insert(ts, row.unit_price)
+        });
+        Ok(())
+    }
+
+    fn store_point(&mut self, key: &str, ts: i64, value: u64) -> Result<(), Error> {
+        self.with(|i| {
+            i.series
+                .entry(key.to_string())
+                .or_default()
+                .insert(ts, value)
+        });
+        Ok(())
+    }
+
+    fn rename_series(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        self.with(|i| {
+            let keys: Vec<String> = i
+                .series
+                .keys()
+                .filter(|k| k.starts_with(from))
+                .cloned()
+                .collect();
+            for key in keys {
+                if let Some(points) = i.series.remove(&key) {
+                    i.series
+                        .insert(format!("{}{}", to, &key[from.len()..]), points);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn store_snapshot(
+        &mut self,
+        market: Market,
+        ts: i64,
+        auctions: &[Auction],
+    ) -> Result<(), Error> {
+        self.with(|i| i.books.insert((market, ts), auctions.to_vec()));
+        Ok(())
+    }
+
+    fn get_snapshot(&mut self, market: Market, ts: i64) -> Result<Vec<Auction>, Error> {
+        Ok(self.with(|i| i.books.get(&(market, ts)).cloned().unwrap_or_default()))
+    }
+
+    fn get_range(&mut self, key: &str) -> Result<Vec<(i64, u64)>, Error> {
+        Ok(self.with(|i| {
+            i.series
+                .get(key)
+                .map(|s| s.iter().map(|(ts, v)| (*ts, *v)).collect())
+                .unwrap_or_default()
+        }))
+    }
+
+    fn store_items(&mut self, items: Vec<Item>) -> Result<usize, Error> {
+        let count = items.len();
+        self.with(|i| {
+            for item in items {
+                for (locale, name) in item.localised_names() {
+                    i.names
+                        .entry((locale.to_string(), sanitise_name(name.to_string())))
+                        .or_default()
+                        .insert(item.id);
+                }
+                i.items.insert(item.id, item);
+            }
+        });
+        Ok(count)
+    }
+
+    fn get_item_metadata(&mut self, id: u64) -> Result<Option<Item>, Error> {
+        Ok(self.with(|i| i.items.get(&id).cloned()))
+    }
+
+    fn store_pets(&mut self, pets: Vec<PetSpecies>) -> Result<usize, Error> {
+        let count = pets.len();
+        self.with(|i| i.pets.extend(pets.into_iter().map(|p| (p.id, p))));
+        Ok(count)
+    }
+
+    fn get_pet(&mut self, species_id: u32) -> Result<Option<PetSpecies>, Error> {
+        Ok(self.with(|i| i.pets.get(&species_id).cloned()))
+    }
+
+    fn get_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        let key = (locale.to_string(), sanitise_name(name.to_string()));
+        Ok(self.with(|i| {
+            i.names
+                .get(&key)
+                .map(|ids| ids.iter().cloned().collect())
+                .unwrap_or_default()
+        }))
+    }
+
+    fn search_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        let prefix = sanitise_name(name.to_string());
+        Ok(self.with(|i| {
+            i.names
+                .range((locale.to_string(), prefix.clone())..)
+                .take_while(|((l, k), _)| l == locale && k.starts_with(&prefix))
+                .flat_map(|(_, ids)| ids.iter().cloned())
+                .collect::<BTreeSet<u64>>()
+                .into_iter()
+                .collect()
+        }))
+    }
+
+    fn add_to_watchlist(&mut self, ids: &[u64]) -> Result<u64, Error> {
+        Ok(self.with(|i| ids.iter().filter(|id| i.watchlist.insert(**id)).count() as u64))
+    }
+
+    fn get_watchlist(&mut self) -> Result<Vec<u64>, Error> {
+        Ok(self.with(|i| i.watchlist.iter().cloned().collect()))
+    }
+
+    fn remove_from_watchlist(&mut self, id: u64) -> Result<bool, Error> {
+        Ok(self.with(|i| i.watchlist.remove(&id)))
+    }
+
+    fn has_flag(&mut self, name: &str) -> Result<bool, Error> {
+        Ok(self.with(|i| i.flags.contains(name)))
+    }
+
+    fn set_flag(&mut self, name: &str) -> Result<(), Error> {
+        self.with(|i| i.flags.insert(name.to_string()));
+        Ok(())
+    }
+
+    fn get_variants(&mut self, item_id: u64) -> Result<Vec<String>, Error> {
+        Ok(self.with(|i| {
+            i.variants
+                .get(&item_id)
+                .map(|v| v.iter().cloned().collect())
+                .unwrap_or_default()
+        }))
+    }
+}
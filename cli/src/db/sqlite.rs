@@ -0,0 +1,593 @@
+use super::{sanitise_name, PriceStore};
+use crate::{
+    actors::AuctionRow,
+    realm::{Auction, AuctionTime, Item, ItemIden, Market, PetSpecies, DEFAULT_LOCALE},
+    AsKey, Error,
+};
+use log::{info, trace};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS auctions (
+    key TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    auction_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL,
+    unit_price INTEGER NOT NULL,
+    PRIMARY KEY (key, ts)
+);
+CREATE TABLE IF NOT EXISTS points (
+    key TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    value INTEGER NOT NULL,
+    PRIMARY KEY (key, ts)
+);
+CREATE TABLE IF NOT EXISTS order_book (
+    market TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    auction_id INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    context INTEGER,
+    quantity INTEGER NOT NULL,
+    unit_price INTEGER,
+    buyout INTEGER,
+    time_left TEXT NOT NULL,
+    item TEXT NOT NULL,
+    PRIMARY KEY (market, ts, auction_id)
+);
+CREATE TABLE IF NOT EXISTS item_variants (
+    item_id INTEGER NOT NULL,
+    variant TEXT NOT NULL,
+    PRIMARY KEY (item_id, variant)
+);
+CREATE TABLE IF NOT EXISTS items (
+    id INTEGER PRIMARY KEY,
+    en_us TEXT NOT NULL,
+    name_key TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS items_name_key ON items (name_key);
+CREATE TABLE IF NOT EXISTS item_details (
+    id INTEGER PRIMARY KEY,
+    quality TEXT,
+    class_id INTEGER,
+    subclass_id INTEGER,
+    level INTEGER,
+    sell_price INTEGER,
+    stackable INTEGER
+);
+CREATE TABLE IF NOT EXISTS item_names (
+    id INTEGER NOT NULL,
+    locale TEXT NOT NULL,
+    name TEXT NOT NULL,
+    name_key TEXT NOT NULL,
+    PRIMARY KEY (id, locale)
+);
+CREATE INDEX IF NOT EXISTS item_names_name_key ON item_names (locale, name_key);
+CREATE TABLE IF NOT EXISTS pet_species (
+    id INTEGER NOT NULL,
+    locale TEXT NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (id, locale)
+);
+CREATE TABLE IF NOT EXISTS watchlist (
+    item_id INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS store_flags (
+    name TEXT PRIMARY KEY
+);
+";
+
+impl FromSql for AuctionTime {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+/// An order book auction's item, stored as JSON
+impl FromSql for ItemIden {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// Prices, item metadata and the watchlist kept in a single embedded SQLite file
+pub struct SqliteStore {
+    con: Connection,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path` and ensure the schema exists
+    pub fn open(path: &str) -> Result<Self, Error> {
+        info!("Opening SQLite store {}", path);
+        Self::init(Connection::open(path)?)
+    }
+
+    fn init(con: Connection) -> Result<Self, Error> {
+        con.execute_batch(SCHEMA)?;
+        Ok(Self { con })
+    }
+
+    /// The ids of items whose name in `locale` matches `clause`, where English names are those in
+    /// `items` and the rest are in `item_names`
+    fn ids_where(&mut self, clause: &str, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        let mut stmt = self.con.prepare(&format!(
+            "SELECT id FROM items WHERE ?1 = '{default}' AND {clause}
+             UNION SELECT id FROM item_names WHERE locale = ?1 AND {clause}
+             ORDER BY id",
+            default = DEFAULT_LOCALE,
+            clause = clause
+        ))?;
+        let ids = stmt
+            .query_map(params![locale, sanitise_name(name.to_string())], |r| {
+                r.get::<_, i64>(0)
+            })?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(ids.into_iter().map(|id| id as u64).collect())
+    }
+}
+
+impl PriceStore for SqliteStore {
+    fn store_auction(&mut self, row: &AuctionRow, ts: i64) -> Result<(), Error> {
+        if let Some(variant) = &row.variant {
+            self.con.execute(
+                "INSERT OR IGNORE INTO item_variants (item_id, variant) VALUES (?1, ?2)",
+                params![row.item_id as i64, variant],
+            )?;
+        }
+        self.con.execute(
+            "INSERT OR REPLACE INTO auctions (key, ts, item_id, auction_id, quantity, unit_price)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                row.to_key(),
+                ts,
+                row.item_id as i64,
+                row.auction_id as i64,
+                row.quantity,
+                row.unit_price as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn store_point(&mut self, key: &str, ts: i64, value: u64) -> Result<(), Error> {
+        self.con.execute(
+            "INSERT OR REPLACE INTO points (key, ts, value) VALUES (?1, ?2, ?3)",
+            params![key, ts, value as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Each book is keyed by its market's namespace, e.g. `eu:1403:item`
+    fn store_snapshot(
+        &mut self,
+        market: Market,
+        ts: i64,
+        auctions: &[Auction],
+    ) -> Result<(), Error> {
+        let market = market.namespace();
+        let tx = self.con.transaction()?;
+        tx.execute(
+            "DELETE FROM order_book WHERE market = ?1 AND ts = ?2",
+            params![market, ts],
+        )?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO order_book
+                 (market, ts, auction_id, item_id, context, quantity, unit_price, buyout,
+                  time_left, item)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?;
+            for a in auctions {
+                stmt.execute(params![
+                    market,
+                    ts,
+                    a.id as i64,
+                    a.item.id as i64,
+                    a.item.context,
+                    a.quantity,
+                    a.unit_price.map(|p| p as i64),
+                    a.buyout.map(|p| p as i64),
+                    a.time_left.to_string(),
+                    serde_json::to_string(&a.item)?
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_snapshot(&mut self, market: Market, ts: i64) -> Result<Vec<Auction>, Error> {
+        let mut stmt = self.con.prepare(
+            "SELECT auction_id, item, quantity, unit_price, buyout, time_left
+             FROM order_book WHERE market = ?1 AND ts = ?2 ORDER BY auction_id",
+        )?;
+        let auctions = stmt
+            .query_map(params![market.namespace(), ts], |r| {
+                Ok(Auction {
+                    id: r.get::<_, i64>(0)? as u64,
+                    item: r.get(1)?,
+                    quantity: r.get(2)?,
+                    unit_price: r.get::<_, Option<i64>>(3)?.map(|p| p as u64),
+                    buyout: r.get::<_, Option<i64>>(4)?.map(|p| p as u64),
+                    time_left: r.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(auctions)
+    }
+
+    fn get_range(&mut self, key: &str) -> Result<Vec<(i64, u64)>, Error> {
+        info!("Get range for {}", key);
+        let mut stmt = self.con.prepare(
+            "SELECT ts, unit_price FROM auctions WHERE key = ?1
+             UNION ALL SELECT ts, value FROM points WHERE key = ?1
+             ORDER BY ts",
+        )?;
+        let points = stmt
+            .query_map(params![key], |r| {
+                Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(points)
+    }
+
+    fn rename_series(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let tx = self.con.transaction()?;
+        for table in &["auctions", "points"] {
+            let renamed = tx.execute(
+                &format!(
+                    "UPDATE {} SET key = ?2 || substr(key, ?3) WHERE substr(key, 1, ?4) = ?1",
+                    table
+                ),
+                params![from, to, from.len() as i64 + 1, from.len() as i64],
+            )?;
+            info!(
+                "Renamed {} {} rows from {}* to {}*",
+                renamed, table, from, to
+            );
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn store_items(&mut self, items: Vec<Item>) -> Result<usize, Error> {
+        let tx = self.con.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO items (id, en_us, name_key) VALUES (?1, ?2, ?3)",
+            )?;
+            let mut details = tx.prepare(
+                "INSERT OR REPLACE INTO item_details
+                 (id, quality, class_id, subclass_id, level, sell_price, stackable)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            let mut names = tx.prepare(
+                "INSERT OR REPLACE INTO item_names (id, locale, name, name_key)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for i in items.iter() {
+                trace!("Store item {}", i.to_key());
+                stmt.execute(params![
+                    i.id as i64,
+                    i.en_us,
+                    sanitise_name(i.en_us.clone())
+                ])?;
+                details.execute(params![
+                    i.id as i64,
+                    i.quality,
+                    i.class_id,
+                    i.subclass_id,
+                    i.level,
+                    i.sell_price.map(|p| p as i64),
+                    i.stackable
+                ])?;
+                for (locale, name) in i.names.iter() {
+                    names.execute(params![
+                        i.id as i64,
+                        locale,
+                        name,
+                        sanitise_name(name.clone())
+                    ])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(items.len())
+    }
+
+    fn get_item_metadata(&mut self, id: u64) -> Result<Option<Item>, Error> {
+        let item = self
+            .con
+            .query_row(
+                "SELECT i.id, i.en_us, d.quality, d.class_id, d.subclass_id, d.level,
+                        d.sell_price, d.stackable
+                 FROM items i LEFT JOIN item_details d ON d.id = i.id
+                 WHERE i.id = ?1",
+                params![id as i64],
+                |r| {
+                    Ok(Item {
+                        id: r.get::<_, i64>(0)? as u64,
+                        en_us: r.get(1)?,
+                        quality: r.get(2)?,
+                        class_id: r.get(3)?,
+                        subclass_id: r.get(4)?,
+                        level: r.get(5)?,
+                        sell_price: r.get::<_, Option<i64>>(6)?.map(|p| p as u64),
+                        stackable: r.get(7)?,
+                        ..Default::default()
+                    })
+                },
+            )
+            .optional()?;
+        match item {
+            Some(mut item) => {
+                let mut stmt = self
+                    .con
+                    .prepare("SELECT locale, name FROM item_names WHERE id = ?1")?;
+                item.names = stmt
+                    .query_map(params![id as i64], |r| Ok((r.get(0)?, r.get(1)?)))?
+                    .collect::<Result<_, _>>()?;
+                Ok(Some(item))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Each species' English name is stored as its `en_US` row, so reads back as one of its names
+    fn store_pets(&mut self, pets: Vec<PetSpecies>) -> Result<usize, Error> {
+        let tx = self.con.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO pet_species (id, locale, name) VALUES (?1, ?2, ?3)",
+            )?;
+            for p in pets.iter() {
+                for (locale, name) in p.names.iter() {
+                    stmt.execute(params![p.id, locale, name])?;
+                }
+                stmt.execute(params![p.id, DEFAULT_LOCALE, p.en_us])?;
+            }
+        }
+        tx.commit()?;
+        Ok(pets.len())
+    }
+
+    fn get_pet(&mut self, species_id: u32) -> Result<Option<PetSpecies>, Error> {
+        let mut stmt = self
+            .con
+            .prepare("SELECT locale, name FROM pet_species WHERE id = ?1")?;
+        let names = stmt
+            .query_map(params![species_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<std::collections::BTreeMap<String, String>, _>>()?;
+        Ok(names.get(DEFAULT_LOCALE).cloned().map(|en_us| PetSpecies {
+            id: species_id,
+            en_us,
+            names,
+        }))
+    }
+
+    fn get_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        self.ids_where("name_key = ?2", locale, name)
+    }
+
+    fn search_ids_for_item(&mut self, locale: &str, name: &str) -> Result<Vec<u64>, Error> {
+        self.ids_where("substr(name_key, 1, length(?2)) = ?2", locale, name)
+    }
+
+    fn add_to_watchlist(&mut self, ids: &[u64]) -> Result<u64, Error> {
+        let tx = self.con.transaction()?;
+        let mut added = 0;
+        for id in ids {
+            added += tx.execute(
+                "INSERT OR IGNORE INTO watchlist (item_id) VALUES (?1)",
+                params![*id as i64],
+            )? as u64;
+        }
+        tx.commit()?;
+        Ok(added)
+    }
+
+    fn get_watchlist(&mut self) -> Result<Vec<u64>, Error> {
+        let mut stmt = self
+            .con
+            .prepare("SELECT item_id FROM watchlist ORDER BY item_id")?;
+        let ids = stmt
+            .query_map(params![], |r| r.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(ids.into_iter().map(|id| id as u64).collect())
+    }
+
+    fn remove_from_watchlist(&mut self, id: u64) -> Result<bool, Error> {
+        Ok(self.con.execute(
+            "DELETE FROM watchlist WHERE item_id = ?1",
+            params![id as i64],
+        )? > 0)
+    }
+
+    fn has_flag(&mut self, name: &str) -> Result<bool, Error> {
+        Ok(self
+            .con
+            .query_row(
+                "SELECT 1 FROM store_flags WHERE name = ?1",
+                params![name],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    fn set_flag(&mut self, name: &str) -> Result<(), Error> {
+        self.con.execute(
+            "INSERT OR IGNORE INTO store_flags (name) VALUES (?1)",
+            params![name],
+        )?;
+        Ok(())
+    }
+
+    fn get_variants(&mut self, item_id: u64) -> Result<Vec<String>, Error> {
+        let mut stmt = self
+            .con
+            .prepare("SELECT variant FROM item_variants WHERE item_id = ?1 ORDER BY variant")?;
+        let variants = stmt
+            .query_map(params![item_id as i64], |r| r.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(variants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteStore;
+    use crate::actors::AuctionRow;
+    use crate::db::PriceStore;
+    use crate::realm::{Auction, AuctionTime, Item, ItemIden, Market, PetSpecies, Region, Target};
+
+    #[test]
+    fn store_and_query() {
+        let mut store = SqliteStore::init(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        store
+            .store_items(vec![
+                Item {
+                    id: 109119,
+                    en_us: "True Iron Ore".to_string(),
+                    ..Default::default()
+                },
+                Item {
+                    id: 109118,
+                    en_us: "Blackrock Ore".to_string(),
+                    names: vec![("de_DE".to_string(), "Schwarzfelserz".to_string())]
+                        .into_iter()
+                        .collect(),
+                    quality: Some("COMMON".to_string()),
+                    class_id: Some(7),
+                    subclass_id: Some(7),
+                    level: Some(1),
+                    sell_price: Some(10),
+                    stackable: Some(true),
+                },
+            ])
+            .unwrap();
+        assert_eq!(
+            store.search_ids_for_item("en_US", "true").unwrap(),
+            vec![109119]
+        );
+        assert_eq!(
+            store.search_ids_for_item("de_DE", "schwarz").unwrap(),
+            vec![109118]
+        );
+        assert_eq!(
+            store
+                .get_item_metadata_by_name("en_US", "Blackrock Ore")
+                .unwrap(),
+            store.get_item_metadata(109118).unwrap()
+        );
+        assert_eq!(
+            store
+                .get_item_metadata(109118)
+                .unwrap()
+                .map(|i| (i.names["de_DE"].clone(), i.stackable)),
+            Some(("Schwarzfelserz".to_string(), Some(true)))
+        );
+        assert_eq!(
+            store.missing_items(&[72092, 109118, 109119]).unwrap(),
+            vec![72092]
+        );
+
+        let squirrel = PetSpecies {
+            id: 39,
+            en_us: "Mechanical Squirrel".to_string(),
+            names: vec![
+                ("de_DE".to_string(), "Mechanisches Eichhörnchen".to_string()),
+                ("en_US".to_string(), "Mechanical Squirrel".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(store.store_pets(vec![squirrel.clone()]).unwrap(), 1);
+        assert_eq!(store.get_pet(39).unwrap(), Some(squirrel));
+        assert_eq!(store.missing_pets(&[39, 2716]).unwrap(), vec![2716]);
+
+        assert_eq!(store.add_to_watchlist(&[109119, 109119]).unwrap(), 1);
+        assert_eq!(store.get_watchlist().unwrap(), vec![109119]);
+        assert!(store.remove_from_watchlist(109119).unwrap());
+        assert!(!store.remove_from_watchlist(109119).unwrap());
+        assert!(store.get_watchlist().unwrap().is_empty());
+        assert!(!store.has_flag("seeded").unwrap());
+        store.set_flag("seeded").unwrap();
+        store.set_flag("seeded").unwrap();
+        assert!(store.has_flag("seeded").unwrap());
+
+        for &(ts, price) in &[(2, 300), (1, 200)] {
+            let row = AuctionRow {
+                market: Market::Realm(Target {
+                    region: Region::Eu,
+                    realm_id: 1403,
+                }),
+                item_id: 109119,
+                auction_id: 1,
+                quantity: 20,
+                unit_price: price,
+                variant: None,
+            };
+            store.store_auction(&row, ts).unwrap();
+        }
+        let row = AuctionRow {
+            market: Market::Realm(Target {
+                region: Region::Eu,
+                realm_id: 1403,
+            }),
+            item_id: 109119,
+            auction_id: 2,
+            quantity: 1,
+            unit_price: 400,
+            variant: Some("b1487.6646".to_string()),
+        };
+        store.store_auction(&row, 2).unwrap();
+        assert_eq!(
+            store.get_variants(109119).unwrap(),
+            vec!["b1487.6646".to_string()]
+        );
+        assert_eq!(
+            store.get_range("auc:eu:1403:item:109119").unwrap(),
+            vec![(1, 200), (2, 300)]
+        );
+        store.store_point("auc:item:72092:median", 1, 50).unwrap();
+        store
+            .rename_series("auc:item:", "auc:eu:1403:item:")
+            .unwrap();
+        assert!(store.get_range("auc:item:72092:median").unwrap().is_empty());
+        assert_eq!(
+            store.get_range("auc:eu:1403:item:72092:median").unwrap(),
+            vec![(1, 50)]
+        );
+        assert_eq!(
+            store.get_range("auc:eu:1403:item:109119").unwrap(),
+            vec![(1, 200), (2, 300)]
+        );
+
+        let book = vec![Auction {
+            id: 7,
+            item: ItemIden {
+                id: 109119,
+                context: None,
+                bonus_lists: vec![6646, 1487],
+                ..Default::default()
+            },
+            buyout: None,
+            unit_price: Some(250),
+            quantity: 200,
+            time_left: AuctionTime::VERY_LONG,
+        }];
+        let draenor = Market::Realm(Target {
+            region: Region::Eu,
+            realm_id: 1403,
+        });
+        store.store_snapshot(draenor, 2, &book).unwrap();
+        assert_eq!(store.get_snapshot(draenor, 2).unwrap(), book);
+        assert!(store.get_snapshot(draenor, 1).unwrap().is_empty());
+    }
+}
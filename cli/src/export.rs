@@ -0,0 +1,246 @@
+//! Exports of auction history for analysis in other tools, e.g. pandas or DuckDB
+use crate::realm::{AuctionResponse, Market};
+use crate::Error;
+use chrono::{DateTime, NaiveDate, Utc};
+use itertools::Itertools;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DataType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde::Serialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A row per auction, in the order the columns are written
+const AUCTION_SCHEMA: &str = "
+message auction {
+    REQUIRED INT64 snapshot (TIMESTAMP(MILLIS, true));
+    REQUIRED INT64 id;
+    REQUIRED INT64 item;
+    OPTIONAL BYTE_ARRAY variant (UTF8);
+    REQUIRED INT32 quantity;
+    OPTIONAL INT64 unit_price;
+    OPTIONAL INT64 buyout;
+    REQUIRED BYTE_ARRAY time_left (UTF8);
+}
+";
+
+/// The directory for a market's day of auctions under `out`, partitioned Hive-style as
+/// `region=eu/realm=1403/day=2020-09-15`, or `realm=commodities`, so readers can filter on them
+pub fn partition(out: &Path, market: Market, day: NaiveDate) -> PathBuf {
+    let realm = match market {
+        Market::Realm(t) => t.realm_id.to_string(),
+        Market::Commodities(_) => "commodities".to_string(),
+    };
+    out.join(format!("region={}", market.region()))
+        .join(format!("realm={}", realm))
+        .join(format!("day={}", day))
+}
+
+/// Writes snapshots to a Parquet file, a row group each.
+///
+/// The file is written under a `.part` suffix and only renamed into place by `finish`.
+pub struct ParquetWriter {
+    path: PathBuf,
+    writer: SerializedFileWriter<File>,
+}
+
+impl ParquetWriter {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = SerializedFileWriter::new(
+            File::create(path.with_extension("parquet.part"))?,
+            Arc::new(parse_message_type(AUCTION_SCHEMA)?),
+            Arc::new(props),
+        )?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+        })
+    }
+
+    /// Write the snapshot taken at `ts`, returning how many auctions it had
+    pub fn write(&mut self, ar: &AuctionResponse, ts: DateTime<Utc>) -> Result<usize, Error> {
+        let auctions = &ar.auctions;
+        if auctions.is_empty() {
+            return Ok(0);
+        }
+        let mut rg = self.writer.next_row_group()?;
+        let ts = ts.timestamp_millis();
+        column::<Int64Type>(&mut rg, &vec![ts; auctions.len()], None)?;
+        let ids: Vec<i64> = auctions.iter().map(|a| a.id as i64).collect();
+        column::<Int64Type>(&mut rg, &ids, None)?;
+        let items: Vec<i64> = auctions.iter().map(|a| a.item.id as i64).collect();
+        column::<Int64Type>(&mut rg, &items, None)?;
+        let (variants, defined) = optional(
+            auctions
+                .iter()
+                .map(|a| a.item.variant().map(|v| ByteArray::from(v.as_str()))),
+        );
+        column::<ByteArrayType>(&mut rg, &variants, Some(&defined))?;
+        let quantities: Vec<i32> = auctions.iter().map(|a| a.quantity as i32).collect();
+        column::<Int32Type>(&mut rg, &quantities, None)?;
+        let (unit_prices, defined) =
+            optional(auctions.iter().map(|a| a.unit_price.map(|p| p as i64)));
+        column::<Int64Type>(&mut rg, &unit_prices, Some(&defined))?;
+        let (buyouts, defined) = optional(auctions.iter().map(|a| a.buyout.map(|p| p as i64)));
+        column::<Int64Type>(&mut rg, &buyouts, Some(&defined))?;
+        let time_left: Vec<ByteArray> = auctions
+            .iter()
+            .map(|a| ByteArray::from(format!("{:?}", a.time_left).as_str()))
+            .collect();
+        column::<ByteArrayType>(&mut rg, &time_left, None)?;
+        rg.close()?;
+        Ok(auctions.len())
+    }
+
+    /// Write the file's footer and move it into place
+    pub fn finish(self) -> Result<(), Error> {
+        self.writer.close()?;
+        std::fs::rename(self.path.with_extension("parquet.part"), &self.path)?;
+        Ok(())
+    }
+}
+
+/// Write the row group's next column, with definition levels if it's optional
+fn column<T: DataType>(
+    rg: &mut SerializedRowGroupWriter<File>,
+    values: &[T::T],
+    defined: Option<&[i16]>,
+) -> Result<(), Error> {
+    let mut col = rg
+        .next_column()?
+        .ok_or_else(|| Error::IOError("More columns written than in the schema".to_string()))?;
+    col.typed::<T>().write_batch(values, defined, None)?;
+    col.close()?;
+    Ok(())
+}
+
+/// The values present, and the definition level of each row: 1 if it has one, 0 if it's null
+fn optional<V>(values: impl Iterator<Item = Option<V>>) -> (Vec<V>, Vec<i16>) {
+    let mut present = vec![];
+    let defined = values
+        .map(|v| match v {
+            Some(v) => {
+                present.push(v);
+                1
+            }
+            None => 0,
+        })
+        .collect();
+    (present, defined)
+}
+
+/// A point of a series as exported, e.g. a row of CSV
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SeriesRow {
+    pub item: u64,
+    pub name: String,
+    pub variant: Option<String>,
+    /// The start of the bucket, or the time of the point if there are no buckets, in seconds
+    pub ts: i64,
+    pub value: u64,
+}
+
+/// A length of time to bucket series points by, e.g. `90s`, `15m`, `1h` or `1d`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket(pub i64);
+
+impl FromStr for Bucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unit = match s.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => return Err(format!("Unknown bucket {}, expected e.g. 15m, 1h or 1d", s)),
+        };
+        match s[..s.len() - 1].parse::<i64>() {
+            Ok(n) if n > 0 => Ok(Bucket(n * unit)),
+            _ => Err(format!("Unknown bucket {}, expected e.g. 15m, 1h or 1d", s)),
+        }
+    }
+}
+
+/// How the points falling in a bucket are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    Min,
+    Max,
+    Avg,
+    First,
+    Last,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(Aggregation::Min),
+            "max" => Ok(Aggregation::Max),
+            "avg" => Ok(Aggregation::Avg),
+            "first" => Ok(Aggregation::First),
+            "last" => Ok(Aggregation::Last),
+            _ => Err(format!(
+                "Unknown aggregation {}, expected min, max, avg, first or last",
+                s
+            )),
+        }
+    }
+}
+
+impl Aggregation {
+    /// Combine a series' points, oldest first, into one per bucket, at the bucket's start
+    pub fn apply(self, points: &[(i64, u64)], bucket: Bucket) -> Vec<(i64, u64)> {
+        points
+            .iter()
+            .group_by(|(ts, _)| ts - ts.rem_euclid(bucket.0))
+            .into_iter()
+            .map(|(start, group)| {
+                let values: Vec<u64> = group.map(|(_, v)| *v).collect();
+                let value = match self {
+                    Aggregation::Min => values.iter().min().copied(),
+                    Aggregation::Max => values.iter().max().copied(),
+                    Aggregation::Avg => Some(values.iter().sum::<u64>() / values.len() as u64),
+                    Aggregation::First => values.first().copied(),
+                    Aggregation::Last => values.last().copied(),
+                };
+                (start, value.unwrap_or_default())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_by_bucket() {
+        let points = [(3600, 10), (4000, 30), (7199, 20), (7200, 5), (90000, 7)];
+        let hour: Bucket = "1h".parse().unwrap();
+        assert_eq!(hour, Bucket(3600));
+        assert_eq!(
+            Aggregation::Avg.apply(&points, hour),
+            vec![(3600, 20), (7200, 5), (90000, 7)]
+        );
+        assert_eq!(
+            Aggregation::Last.apply(&points, "1d".parse().unwrap()),
+            vec![(0, 5), (86400, 7)]
+        );
+        assert_eq!(Aggregation::Min.apply(&points, hour)[0], (3600, 10));
+        assert!("0h".parse::<Bucket>().is_err());
+        assert!("1w".parse::<Bucket>().is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::Error;
+use log::warn;
+use rand::Rng;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+/// The longest wait between attempts when backing off
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// The HTTP client shared by all battle.net API calls.
+///
+/// Spaces requests out to stay within a requests-per-second budget and retries timeouts,
+/// connection failures, 5xx and 429 responses, backing off exponentially with jitter in between.
+/// A 429 holds back every request sharing the budget for as long as its `Retry-After` asks.
+#[derive(Clone)]
+pub struct Api {
+    client: Client,
+    /// The least time between the start of two requests
+    interval: Duration,
+    /// When the next request may be sent
+    next_slot: Arc<Mutex<Instant>>,
+    max_retries: u32,
+    base_delay: Duration,
+    /// The longest `Retry-After` that's honoured
+    max_retry_after: Duration,
+}
+
+impl std::fmt::Debug for Api {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Api")
+            .field("interval", &self.interval)
+            .field("max_retries", &self.max_retries)
+            .field("max_retry_after", &self.max_retry_after)
+            .finish()
+    }
+}
+
+impl Api {
+    pub fn new(requests_per_sec: f64, max_retries: u32, max_retry_after: Duration) -> Self {
+        Self {
+            client: Client::new(),
+            interval: Duration::from_secs_f64(1.0 / requests_per_sec.max(0.001)),
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+            max_retries,
+            base_delay: Duration::from_millis(500),
+            max_retry_after,
+        }
+    }
+
+    /// Send the request built by `build`, retrying it until it succeeds or it's clear it won't.
+    ///
+    /// Any response other than a 429 or 5xx is returned as is, for the caller to interpret.
+    pub async fn send<F>(&self, build: F) -> Result<Response, Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            self.throttle().await;
+            let (wait, last) = match build(&self.client).send().await {
+                Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let wait = self.rate_limited(retry_after(&res), attempt);
+                    (wait, format!("{} from {}", res.status(), res.url()))
+                }
+                Ok(res) if res.status().is_server_error() => (
+                    self.backoff(attempt),
+                    format!("{} from {}", res.status(), res.url()),
+                ),
+                Ok(res) => return Ok(res),
+                Err(e) if e.is_timeout() || e.is_connect() => {
+                    (self.backoff(attempt), format!("{}", e))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            attempt += 1;
+            if attempt > self.max_retries {
+                return Err(Error::RetriesExhausted {
+                    attempts: attempt,
+                    last,
+                });
+            }
+            warn!(
+                "Attempt {} failed ({}), retrying in {:?}",
+                attempt, last, wait
+            );
+            delay_for(wait).await;
+        }
+    }
+
+    /// Wait for the next free slot in the request budget
+    async fn throttle(&self) {
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next_slot.lock().expect("Rate limiter lock poisoned");
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot
+        };
+        if slot > now {
+            delay_for(slot - now).await;
+        }
+    }
+
+    /// Hold back all requests for as long as a 429 asks, up to `max_retry_after`, or the backoff
+    /// for `attempt` if it doesn't say, returning the wait
+    fn rate_limited(&self, retry_after: Option<Duration>, attempt: u32) -> Duration {
+        let wait = retry_after
+            .map(|wait| wait.min(self.max_retry_after))
+            .unwrap_or_else(|| self.backoff(attempt));
+        let resume = Instant::now() + wait;
+        let mut next = self.next_slot.lock().expect("Rate limiter lock poisoned");
+        *next = (*next).max(resume);
+        wait
+    }
+
+    /// Half the exponential delay for `attempt`, plus a random amount up to the other half
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = (self.base_delay * 2u32.saturating_pow(attempt.min(16))).min(MAX_DELAY);
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+    }
+}
+
+/// How long a 429 response asks us to wait, given either in seconds or as an HTTP date
+fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    parse_retry_after(value, chrono::Utc::now())
+}
+
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => crate::realm::parse_http_date(value).map(|t| {
+            (t - now)
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_and_backoff() {
+        let now = crate::realm::parse_http_date("Tue, 15 Sep 2020 09:31:02 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Tue, 15 Sep 2020 09:31:32 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Tue, 15 Sep 2020 09:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        let api = Api::new(10.0, 3, Duration::from_secs(120));
+        for attempt in 0..20 {
+            let delay = api.backoff(attempt);
+            let full = (api.base_delay * 2u32.pow(attempt.min(16))).min(MAX_DELAY);
+            assert!(delay >= full / 2 && delay <= full, "{:?} {:?}", delay, full);
+        }
+    }
+
+    #[test]
+    fn rate_limits_hold_back_every_request() {
+        let api = Api::new(10.0, 3, Duration::from_secs(120));
+        let other = api.clone();
+        let started = Instant::now();
+        assert_eq!(
+            api.rate_limited(Some(Duration::from_secs(300)), 0),
+            Duration::from_secs(120)
+        );
+        assert!(*other.next_slot.lock().unwrap() >= started + Duration::from_secs(120));
+
+        // A shorter wait doesn't bring the next slot back
+        assert_eq!(
+            api.rate_limited(Some(Duration::from_secs(2)), 0),
+            Duration::from_secs(2)
+        );
+        assert!(*other.next_slot.lock().unwrap() >= started + Duration::from_secs(120));
+
+        let backoff = api.rate_limited(None, 1);
+        assert!(backoff >= api.base_delay && backoff <= api.base_delay * 2);
+    }
+}
@@ -0,0 +1,133 @@
+//! A record of the archives `load` has ingested into each storage backend, so a re-run skips
+//! them and a load that crashed part way through a directory picks up where it stopped.
+use crate::Error;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// One archive loaded into one backend, a line of the ledger file
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// See `db::store_id`
+    backend: String,
+    /// The archive's path relative to `data_dir`, e.g. `eu/1403/2020-09-15T09:31:02+00:00.zst`
+    file: String,
+    sha256: String,
+    /// RFC 3339
+    loaded_at: String,
+}
+
+/// The archives loaded into a single backend, appended to as each one completes
+pub struct Ledger {
+    path: String,
+    backend: String,
+    /// The hash of each archive as it was when loaded, by file
+    loaded: HashMap<String, String>,
+}
+
+impl Ledger {
+    /// The ledger at `path` as it stands for `backend`, empty if there's no such file yet
+    pub fn open(path: &str, backend: &str) -> Result<Self, Error> {
+        let mut loaded = HashMap::new();
+        match std::fs::read_to_string(path) {
+            Ok(lines) => {
+                // The last line is lost if a crash cut it short, so the archive is loaded again.
+                // `record` starts a new line after it.
+                for line in lines.lines() {
+                    match serde_json::from_str::<Entry>(line) {
+                        Ok(e) if e.backend == backend => {
+                            loaded.insert(e.file, e.sha256);
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Ignoring unreadable ledger entry {:?}: {}", line, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        info!("{} archives loaded into {} so far", loaded.len(), backend);
+        Ok(Self {
+            path: path.to_string(),
+            backend: backend.to_string(),
+            loaded,
+        })
+    }
+
+    /// Whether the archive was loaded with these contents
+    pub fn is_loaded(&self, file: &str, sha256: &str) -> bool {
+        self.loaded.get(file).map(String::as_str) == Some(sha256)
+    }
+
+    /// Note the archive as loaded, writing it through to the ledger file at once
+    pub fn record(&mut self, file: &str, sha256: &str) -> Result<(), Error> {
+        let entry = Entry {
+            backend: self.backend.clone(),
+            file: file.to_string(),
+            sha256: sha256.to_string(),
+            loaded_at: Utc::now().to_rfc3339(),
+        };
+        let mut ledger = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(&entry)? + "\n";
+        // Don't run on from a line a crash cut short
+        if ledger.seek(SeekFrom::End(0))? > 0 {
+            let mut last = [0u8];
+            ledger.seek(SeekFrom::End(-1))?;
+            ledger.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                line.insert(0, '\n');
+            }
+        }
+        ledger.write_all(line.as_bytes())?;
+        ledger.sync_data()?;
+        self.loaded.insert(entry.file, entry.sha256);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ledger;
+
+    #[test]
+    fn records_per_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.jsonl");
+        let path = path.to_str().unwrap();
+
+        let mut sqlite = Ledger::open(path, "sqlite:waw.sqlite").unwrap();
+        sqlite.record("eu/1403/a.xz", "aaa").unwrap();
+        sqlite.record("eu/1403/b.xz", "bbb").unwrap();
+        Ledger::open(path, "redis://localhost")
+            .unwrap()
+            .record("eu/1403/a.xz", "aaa")
+            .unwrap();
+        // As if a crash cut the last entry short
+        let mut lines = std::fs::read_to_string(path).unwrap();
+        lines.push_str("{\"backend\":\"sqlite:waw.sqlite\",\"fi");
+        std::fs::write(path, lines).unwrap();
+
+        let sqlite = Ledger::open(path, "sqlite:waw.sqlite").unwrap();
+        assert!(sqlite.is_loaded("eu/1403/a.xz", "aaa"));
+        assert!(sqlite.is_loaded("eu/1403/b.xz", "bbb"));
+        assert!(!sqlite.is_loaded("eu/1403/b.xz", "changed"));
+        let redis = Ledger::open(path, "redis://localhost").unwrap();
+        assert!(!redis.is_loaded("eu/1403/b.xz", "bbb"));
+
+        // Recorded after the cut short entry, not run on from it
+        Ledger::open(path, "sqlite:waw.sqlite")
+            .unwrap()
+            .record("eu/1403/c.xz", "ccc")
+            .unwrap();
+        let sqlite = Ledger::open(path, "sqlite:waw.sqlite").unwrap();
+        assert!(sqlite.is_loaded("eu/1403/c.xz", "ccc"));
+        assert!(sqlite.is_loaded("eu/1403/b.xz", "bbb"));
+    }
+}
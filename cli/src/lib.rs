@@ -1,11 +1,20 @@
 pub mod actors;
+pub mod codec;
 pub mod db;
+pub mod export;
+pub mod http;
+pub mod ledger;
+pub mod listings;
+pub mod migrate;
 pub mod realm;
+pub mod sales;
+pub mod snapshot;
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use clap::Clap;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// Represent this instance as a uniquely identifiable item in some store, i.e. convert to a key
 pub trait AsKey {
@@ -38,8 +47,12 @@ pub struct Settings {
     /// Secret
     pub client_secret: String,
 
-    /// The realm id, e.g. 1403 = Draenor
-    pub realm_id: u16,
+    /// The realm id, e.g. 1403 = Draenor; shorthand for a single EU entry in `targets`
+    pub realm_id: Option<u16>,
+
+    /// The connected realms, and their regions, to sync
+    #[serde(default)]
+    pub targets: Vec<realm::Target>,
 
     /// The parent directory for all data
     pub data_dir: String,
@@ -52,9 +65,63 @@ pub struct Settings {
 
     /// The hostname for the redis database
     pub db_host: String,
+
+    /// Where prices are stored, `redis` (the default) or `sqlite`
+    #[serde(default)]
+    pub backend: db::Backend,
+
+    /// The SQLite database file, defaulting to `waw.sqlite` in `data_dir`
+    pub sqlite_path: Option<String>,
+
+    /// Whether to save access tokens in `data_dir`, so restarts can re-use them
+    #[serde(default)]
+    pub persist_token: bool,
+
+    /// The most battle.net API requests to send per second, 10 by default
+    pub requests_per_sec: Option<f64>,
+
+    /// How many times to retry a failed API request before giving up, 5 by default
+    pub max_retries: Option<u32>,
+
+    /// The longest a 429's `Retry-After` is waited for, in seconds, 300 by default
+    pub max_retry_after_secs: Option<u64>,
+
+    /// The base URL of the Game Data APIs, replacing `https://{region}.api.blizzard.com`
+    pub api_url: Option<String>,
+
+    /// The base URL of the OAuth token endpoint, replacing the region's battle.net one
+    pub oauth_url: Option<String>,
+
+    /// The most items to look up the metadata of after each snapshot, 200 by default
+    pub item_fetch_limit: Option<usize>,
+
+    /// The most battle pet species to look up after each snapshot, 200 by default
+    pub pet_fetch_limit: Option<usize>,
+
+    /// Whether to archive each snapshot's raw `.json` beside the compressed archive, true by default
+    pub archive_json: Option<bool>,
+
+    /// How long to keep archived snapshots, for good if unset
+    pub retention: Option<snapshot::Retention>,
+
+    /// How to compress archived snapshots, `zstd` (the default), `xz` or `gzip`
+    #[serde(default)]
+    pub archive_codec: codec::Codec,
+
+    /// A zstd dictionary trained on auction JSON, e.g. with `zstd --train`, to archive with and
+    /// to read the archives written with it
+    pub zstd_dictionary: Option<String>,
 }
 
 impl Settings {
+    /// The contents of the `zstd_dictionary`, if there is one
+    pub fn zstd_dictionary(&self) -> Result<Option<Vec<u8>>, Error> {
+        match &self.zstd_dictionary {
+            Some(path) => Ok(Some(std::fs::read(path)?)),
+            None => Ok(None),
+        }
+    }
+
     pub fn new() -> Result<Self, config::ConfigError> {
         Self::from("Settings")
     }
@@ -68,6 +135,61 @@ impl Settings {
             .merge(config::Environment::with_prefix("WAW"))?;
         settings.try_into()
     }
+
+    /// Every connected realm to sync, including the one given by `realm_id`
+    pub fn targets(&self) -> Vec<realm::Target> {
+        let mut targets = self.targets.clone();
+        if let Some(realm_id) = self.realm_id {
+            targets.insert(
+                0,
+                realm::Target {
+                    region: realm::Region::Eu,
+                    realm_id,
+                },
+            );
+        }
+        targets
+    }
+
+    /// The SQLite database file to use for the `sqlite` backend
+    pub fn sqlite_path(&self) -> String {
+        self.sqlite_path
+            .clone()
+            .unwrap_or_else(|| format!("{}/waw.sqlite", self.data_dir))
+    }
+
+    /// The base URL of the region's Game Data APIs
+    pub fn api_url(&self, region: realm::Region) -> String {
+        self.api_url
+            .clone()
+            .unwrap_or_else(|| format!("https://{}", region.api_host()))
+    }
+
+    /// The base URL of the region's OAuth token endpoint
+    pub fn oauth_url(&self, region: realm::Region) -> String {
+        self.oauth_url
+            .clone()
+            .unwrap_or_else(|| format!("https://{}", region.oauth_host()))
+    }
+
+    /// How many items' metadata to look up after each snapshot
+    pub fn item_fetch_limit(&self) -> usize {
+        self.item_fetch_limit.unwrap_or(200)
+    }
+
+    /// How many battle pet species to look up after each snapshot
+    pub fn pet_fetch_limit(&self) -> usize {
+        self.pet_fetch_limit.unwrap_or(200)
+    }
+
+    /// The HTTP client for the battle.net APIs, limited as configured
+    pub fn api(&self) -> http::Api {
+        http::Api::new(
+            self.requests_per_sec.unwrap_or(10.0),
+            self.max_retries.unwrap_or(5),
+            std::time::Duration::from_secs(self.max_retry_after_secs.unwrap_or(300)),
+        )
+    }
 }
 
 #[derive(Clap, Clone)]
@@ -83,8 +205,195 @@ pub enum SubCmd {
     /// Continuously download auction house and other game data
     #[clap()]
     Sync(SyncOpts),
-    /// Load to a Redis instance using raw protocol messages (for `redis-cli --pipe`)
-    Load,
+    /// Replay archived snapshots into the database, as if they'd just been sync'd
+    Load(LoadOpts),
+    /// Find items by the start of their name
+    Search(SearchOpts),
+    /// Look after the archived snapshots
+    Archive(ArchiveOpts),
+    /// Export auction history for analysis in other tools
+    Export(ExportOpts),
+}
+
+#[derive(Clap, Clone)]
+pub struct ExportOpts {
+    #[clap(subcommand)]
+    pub cmd: ExportCmd,
+}
+
+#[derive(Clap, Clone)]
+pub enum ExportCmd {
+    /// Write every archived auction to Parquet, a file per realm and day
+    Parquet(ParquetOpts),
+    /// Write items' stored price series as CSV or newline-delimited JSON
+    Series(SeriesOpts),
+}
+
+#[derive(Clap, Clone)]
+pub struct SeriesOpts {
+    /// The items, by id or by name in `locale`
+    #[clap(required = true)]
+    pub items: Vec<String>,
+
+    /// The locale the names are in, e.g. de_DE
+    #[clap(short, long, default_value = "en_US")]
+    pub locale: String,
+
+    /// Only export points at or after this time, e.g. 2020-09-15T00:00:00Z
+    #[clap(long)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only export points before this time
+    #[clap(long)]
+    pub until: Option<DateTime<Utc>>,
+
+    /// Combine the points in each bucket of this length, e.g. 1h or 1d
+    #[clap(short, long)]
+    pub bucket: Option<export::Bucket>,
+
+    /// How to combine the points in each bucket: min, max, avg, first or last
+    #[clap(long, default_value = "avg")]
+    pub aggregation: export::Aggregation,
+
+    /// A market statistic to export rather than the best price, e.g. median or listings
+    #[clap(long)]
+    pub stat: Option<String>,
+
+    /// The variant to export, e.g. `b1487.6646`, rather than the plain item
+    #[clap(long)]
+    pub variant: Option<String>,
+
+    /// The connected realm, the first of `targets` by default
+    #[clap(long)]
+    pub realm: Option<u16>,
+
+    /// Export the region's commodity prices rather than a realm's
+    #[clap(long)]
+    pub commodities: bool,
+
+    /// `csv` or `ndjson`
+    #[clap(short, long, default_value = "csv")]
+    pub format: SeriesFormat,
+
+    /// The file to write to, rather than stdout
+    #[clap(short, long)]
+    pub out: Option<String>,
+}
+
+/// How `export series` writes points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeriesFormat {
+    Csv,
+    Ndjson,
+}
+
+impl std::str::FromStr for SeriesFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(SeriesFormat::Csv),
+            "ndjson" => Ok(SeriesFormat::Ndjson),
+            _ => Err(format!("Unknown format {}, expected csv or ndjson", s)),
+        }
+    }
+}
+
+#[derive(Clap, Clone)]
+pub struct ParquetOpts {
+    /// Only export the days from this time's on, e.g. 2020-09-15T00:00:00Z
+    #[clap(long)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only export the days before this time, and its own day if it's after midnight
+    #[clap(long)]
+    pub until: Option<DateTime<Utc>>,
+
+    /// The directory to write to, `parquet` in `data_dir` by default
+    #[clap(short, long)]
+    pub out: Option<String>,
+}
+
+#[derive(Clap, Clone)]
+pub struct ArchiveOpts {
+    #[clap(subcommand)]
+    pub cmd: ArchiveCmd,
+}
+
+#[derive(Clap, Clone)]
+pub enum ArchiveCmd {
+    /// Check every archive decodes and matches the hash and auction count in its manifest
+    Verify(VerifyOpts),
+    /// Delete the archives that have aged out of the `retention` policy in the settings, and the
+    /// raw `.json` of the rest if `archive_json` is off
+    Prune(PruneOpts),
+}
+
+#[derive(Clap, Clone)]
+pub struct PruneOpts {
+    /// List what would be deleted, without deleting it
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clap, Clone)]
+pub struct VerifyOpts {
+    /// Move corrupt archives into a `quarantine` directory beside them
+    #[clap(long)]
+    pub quarantine: bool,
+}
+
+#[derive(Clap, Clone)]
+pub struct SearchOpts {
+    /// The start of the item's name
+    pub name: String,
+
+    /// The locale the name is in, e.g. de_DE
+    #[clap(short, long, default_value = "en_US")]
+    pub locale: String,
+}
+
+#[derive(Clap, Clone)]
+pub struct LoadOpts {
+    /// Only replay snapshots taken at or after this time, e.g. 2020-09-15T00:00:00Z
+    #[clap(long)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only replay snapshots taken before this time
+    #[clap(long)]
+    pub until: Option<DateTime<Utc>>,
+
+    /// Store every auction in each snapshot, not just the best price per item
+    #[clap(short, long)]
+    pub order_book: bool,
+
+    /// `store` to load into the configured backend, or `resp` to print the best prices as Redis
+    /// protocol messages for `redis-cli --pipe`
+    #[clap(long, default_value = "store")]
+    pub format: LoadFormat,
+
+    /// Re-ingest snapshots in the window even if the ledger has them as loaded already
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Where `load` sends the snapshots it replays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadFormat {
+    Store,
+    Resp,
+}
+
+impl std::str::FromStr for LoadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "store" => Ok(LoadFormat::Store),
+            "resp" => Ok(LoadFormat::Resp),
+            _ => Err(format!("Unknown format {}, expected store or resp", s)),
+        }
+    }
 }
 
 #[derive(Clap, Clone)]
@@ -92,6 +401,18 @@ pub struct SyncOpts {
     /// Don't load in to the database on-the-fly
     #[clap(short, long)]
     pub no_load: bool,
+
+    /// Load in to memory only, discarding everything on exit
+    #[clap(short, long)]
+    pub ephemeral: bool,
+
+    /// Store every auction in each snapshot, not just the best price per item
+    #[clap(short, long)]
+    pub order_book: bool,
+
+    /// Skip the region-wide commodities auction house
+    #[clap(long)]
+    pub no_commodities: bool,
 }
 
 /// An period of authenticated interaction with the battle.net APIs
@@ -106,19 +427,57 @@ pub struct Session {
     /// Secret
     client_secret: String,
 
-    /// The realm id, e.g. 1403 = Draenor
-    realm_id: u16,
+    /// The region the session's token was issued for
+    region: realm::Region,
 
     auth: Auth,
+
+    /// The base URL to send Game Data API requests to
+    api_url: String,
+
+    /// The client to make requests with
+    api: http::Api,
 }
 
 impl Session {
     pub fn has_expired(&self) -> bool {
-        (self.start_time + Duration::seconds(self.auth.expires_in.into())) < Utc::now()
+        self.expires_within(Duration::zero())
+    }
+
+    /// Whether the token expires, or has already, within `margin` of now
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        (self.start_time + Duration::seconds(self.auth.expires_in.into()) - margin) < Utc::now()
+    }
+
+    /// The URLs leave out the access token, which is sent as a bearer token so it's never logged
+    fn auction_url(&self, realm_id: u16) -> String {
+        let url = format!(
+            "{}/data/wow/connected-realm/{}/auctions?namespace=dynamic-{}",
+            self.api_url, realm_id, self.region
+        );
+        info!("url: {:?}", url);
+        url
     }
 
-    fn auction_url(&self) -> String {
-        let url = format!("https://eu.api.blizzard.com/data/wow/connected-realm/{}/auctions?namespace=dynamic-eu&locale=en_US&access_token={}", self.realm_id, self.auth.access_token);
+    fn item_url(&self, id: u64) -> String {
+        format!(
+            "{}/data/wow/item/{}?namespace=static-{}",
+            self.api_url, id, self.region
+        )
+    }
+
+    fn pet_url(&self, species_id: u32) -> String {
+        format!(
+            "{}/data/wow/pet/{}?namespace=static-{}",
+            self.api_url, species_id, self.region
+        )
+    }
+
+    fn commodities_url(&self) -> String {
+        let url = format!(
+            "{}/data/wow/auctions/commodities?namespace=dynamic-{}",
+            self.api_url, self.region
+        );
         info!("url: {:?}", url);
         url
     }
@@ -127,34 +486,256 @@ impl Session {
 /// See https://develop.battle.net/documentation/guides/using-oauth/client-credentials-flow
 /// curl -u {client_id}:{client_secret} -d grant_type=client_credentials https://us.battle.net/oauth/token
 pub async fn authenticate(
+    api: &http::Api,
+    oauth_url: &str,
     client_id: String,
     client_secret: String,
-) -> Result<Auth, reqwest::Error> {
-    let client = reqwest::Client::new();
-    let auth = client
-        .post("https://eu.battle.net/oauth/token")
-        .basic_auth(client_id, Some(client_secret))
-        .query(&[("grant_type", "client_credentials")])
-        .send()
-        .await?
-        .text()
+) -> Result<Auth, Error> {
+    let url = format!("{}/oauth/token", oauth_url);
+    let res = api
+        .send(|client| {
+            client
+                .post(&url)
+                .basic_auth(&client_id, Some(&client_secret))
+                .query(&[("grant_type", "client_credentials")])
+        })
         .await?;
-
-    info!("Response: {:?}", auth);
-    Ok(serde_json::from_str(&auth).expect("Failed parsing auth response"))
+    let status = res.status();
+    let auth = res.text().await?;
+
+    info!("Response: {} {:?}", status, auth);
+    if !status.is_success() {
+        return Err(Error::ApiFailure(format!(
+            "Authentication failed with {}: {}",
+            status, auth
+        )));
+    }
+    Ok(serde_json::from_str(&auth)?)
 }
 
-/// Authenticate and initiate a `Session`
-pub async fn get_session(opts: Settings) -> Result<Session, reqwest::Error> {
+/// Authenticate and initiate a `Session` for the region
+pub async fn get_session(
+    api: &http::Api,
+    opts: Settings,
+    region: realm::Region,
+) -> Result<Session, Error> {
     Ok(Session {
         start_time: Utc::now(),
-        auth: authenticate(opts.client_id.clone(), opts.client_secret.clone()).await?,
+        auth: authenticate(
+            api,
+            &opts.oauth_url(region),
+            opts.client_id.clone(),
+            opts.client_secret.clone(),
+        )
+        .await?,
+        api_url: opts.api_url(region),
         client_id: opts.client_id,
         client_secret: opts.client_secret,
-        realm_id: opts.realm_id,
+        region,
+        api: api.clone(),
     })
 }
 
+/// How long before its expiry a token is replaced
+const TOKEN_REFRESH_MARGIN_SECS: i64 = 300;
+
+/// A token as persisted to `data_dir`
+#[derive(Deserialize, Serialize)]
+struct StoredToken {
+    /// Seconds since the epoch at which the token was issued
+    start_time: i64,
+    auth: Auth,
+}
+
+/// Hands out a `Session` per region, re-using each token until shortly before it expires
+pub struct SessionManager {
+    settings: Settings,
+    api: http::Api,
+    sessions: std::sync::Mutex<HashMap<realm::Region, Session>>,
+    /// The upstream update time of the latest snapshot fetched for each market
+    last_modified: std::sync::Mutex<HashMap<realm::Market, DateTime<Utc>>>,
+}
+
+impl SessionManager {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            api: settings.api(),
+            settings,
+            sessions: Default::default(),
+            last_modified: Default::default(),
+        }
+    }
+
+    /// A session for the region, authenticating if there's none or its token is about to expire
+    pub async fn session(&self, region: realm::Region) -> Result<Session, Error> {
+        let cached = self
+            .sessions
+            .lock()
+            .expect("Session cache lock poisoned")
+            .get(&region)
+            .cloned();
+        let cached = cached.or_else(|| self.load_token(region));
+        match cached {
+            Some(s) if !s.expires_within(Duration::seconds(TOKEN_REFRESH_MARGIN_SECS)) => Ok(s),
+            _ => self.refresh(region).await,
+        }
+    }
+
+    /// Authenticate afresh, replacing any session held for the region
+    pub async fn refresh(&self, region: realm::Region) -> Result<Session, Error> {
+        info!("Authenticating for {}", region);
+        let session = get_session(&self.api, self.settings.clone(), region).await?;
+        self.store_token(&session);
+        self.cache(session.clone());
+        Ok(session)
+    }
+
+    /// Make an API call with the region's session, re-authenticating once should the token be
+    /// rejected
+    async fn with_session<T, F, Fut>(&self, region: realm::Region, call: F) -> Result<T, Error>
+    where
+        F: Fn(Session) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
+    {
+        match call(self.session(region).await?).await {
+            Err(Error::Unauthorized) => {
+                warn!("Token rejected for {}, re-authenticating", region);
+                call(self.refresh(region).await?).await
+            }
+            res => res,
+        }
+    }
+
+    /// Look up an item's metadata, yielding `None` if the region has no such item
+    pub async fn item(&self, region: realm::Region, id: u64) -> Result<Option<realm::Item>, Error> {
+        use realm::Realm;
+        self.with_session(region, |s| async move { s.item(id).await })
+            .await
+    }
+
+    /// Look up a battle pet species, yielding `None` if the region has no such species
+    pub async fn pet(
+        &self,
+        region: realm::Region,
+        species_id: u32,
+    ) -> Result<Option<realm::PetSpecies>, Error> {
+        use realm::Realm;
+        self.with_session(region, |s| async move { s.pet(species_id).await })
+            .await
+    }
+
+    /// Fetch the market's auctions as their `Listings`, re-authenticating once should the token be
+    /// rejected, and archive them in the market's directory under `data_dir`.
+    ///
+    /// Yields `None` if the market hasn't been updated since the last snapshot fetched.
+    pub async fn auctions(
+        &self,
+        market: realm::Market,
+    ) -> Result<Option<listings::Listings>, Error> {
+        use realm::Realm;
+        let since = self
+            .last_modified
+            .lock()
+            .expect("Last-Modified lock poisoned")
+            .get(&market)
+            .cloned();
+        let archive_dir = market.archive_dir(&self.settings.data_dir);
+        let dictionary = self.settings.zstd_dictionary()?;
+        let archive = snapshot::ArchiveTo {
+            dir: archive_dir.as_str(),
+            json: self.settings.archive_json.unwrap_or(true),
+            codec: self.settings.archive_codec,
+            dictionary: dictionary.as_deref(),
+        };
+        let res = self
+            .with_session(market.region(), |s| async move {
+                s.market(market, since, Some(archive)).await
+            })
+            .await?;
+        match res {
+            Some(listings) => {
+                if let Some(lm) = listings.last_modified {
+                    self.last_modified
+                        .lock()
+                        .expect("Last-Modified lock poisoned")
+                        .insert(market, lm);
+                }
+                Ok(Some(listings))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn cache(&self, session: Session) {
+        self.sessions
+            .lock()
+            .expect("Session cache lock poisoned")
+            .insert(session.region, session);
+    }
+
+    fn token_path(&self, region: realm::Region) -> Option<String> {
+        if self.settings.persist_token {
+            Some(format!("{}/token-{}.json", self.settings.data_dir, region))
+        } else {
+            None
+        }
+    }
+
+    /// The session persisted for the region, if any
+    fn load_token(&self, region: realm::Region) -> Option<Session> {
+        let path = self.token_path(region)?;
+        let stored: StoredToken = serde_json::from_str(&std::fs::read_to_string(&path).ok()?)
+            .map_err(|e| warn!("Ignoring unreadable token {}: {}", path, e))
+            .ok()?;
+        let session = Session {
+            start_time: Utc.timestamp_opt(stored.start_time, 0).single()?,
+            client_id: self.settings.client_id.clone(),
+            client_secret: self.settings.client_secret.clone(),
+            region,
+            auth: stored.auth,
+            api_url: self.settings.api_url(region),
+            api: self.api.clone(),
+        };
+        info!("Loaded token for {} from {}", region, path);
+        self.cache(session.clone());
+        Some(session)
+    }
+
+    fn store_token(&self, session: &Session) {
+        if let Some(path) = self.token_path(session.region) {
+            let stored = StoredToken {
+                start_time: session.start_time.timestamp(),
+                auth: session.auth.clone(),
+            };
+            if let Err(e) = serde_json::to_string(&stored)
+                .map_err(Error::from)
+                .and_then(|json| write_private(&path, &json))
+            {
+                warn!("Failed to persist token to {}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+/// Write a file only its owner can read, as tokens are credentials
+fn write_private(path: &str, contents: &str) -> Result<(), Error> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // The mode only applies to new files
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        (&file).write_all(contents.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    options.open(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Auth {
     access_token: String,
@@ -170,8 +751,17 @@ pub struct Auth {
 pub enum Error {
     ApiFailure(String),
     AuctionLookup(&'static str),
+    /// The API rejected the access token
+    Unauthorized,
+    /// An API request kept failing, or being rate limited, however many times it was retried
+    RetriesExhausted {
+        attempts: u32,
+        last: String,
+    },
     ConfigError(String),
     IOError(String),
+    /// An archived snapshot that doesn't decode, or doesn't match its manifest
+    CorruptArchive(String),
 }
 
 impl From<reqwest::Error> for Error {
@@ -209,3 +799,84 @@ impl From<redis::RedisError> for Error {
         Error::IOError(format!("Redis error - {:?}", e))
     }
 }
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::IOError(format!("CSV error - {:?}", e))
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::IOError(format!("SQLite error - {:?}", e))
+    }
+}
+
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Error::IOError(format!("Parquet error - {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reuses_persisted_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path();
+        let settings = Settings {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            realm_id: Some(1403),
+            targets: vec![],
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            delay_mins: 60,
+            save_flexbuffer: false,
+            db_host: "localhost".to_string(),
+            backend: db::Backend::Memory,
+            sqlite_path: None,
+            persist_token: true,
+            requests_per_sec: None,
+            max_retries: None,
+            max_retry_after_secs: None,
+            api_url: None,
+            oauth_url: None,
+            item_fetch_limit: None,
+            pet_fetch_limit: None,
+            archive_json: None,
+            retention: None,
+            archive_codec: Default::default(),
+            zstd_dictionary: None,
+        };
+        let stored = StoredToken {
+            start_time: Utc::now().timestamp(),
+            auth: Auth {
+                access_token: "cached".to_string(),
+                token_type: "bearer".to_string(),
+                expires_in: 86399,
+                scope: None,
+            },
+        };
+        std::fs::write(
+            data_dir.join("token-eu.json"),
+            serde_json::to_string(&stored).unwrap(),
+        )
+        .unwrap();
+
+        let sessions = SessionManager::new(settings);
+        let session = sessions.session(realm::Region::Eu).await.unwrap();
+        assert_eq!(session.auth.access_token, "cached");
+        assert!(!session.has_expired());
+        assert!(session.expires_within(Duration::days(1)));
+
+        sessions.store_token(&session);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let meta = std::fs::metadata(data_dir.join("token-eu.json")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
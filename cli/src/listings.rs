@@ -0,0 +1,145 @@
+//! What's kept of a snapshot as its auctions are parsed, instead of the auctions themselves: a
+//! few numbers per listing, enough to price each item variant and to estimate sales against the
+//! next snapshot.
+use crate::actors::AuctionRow;
+use crate::realm::{Auction, AuctionTime, Market, MarketStats};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+
+/// An item id and its variant, see `ItemIden::variant`
+pub type Variant = (u64, Option<String>);
+
+/// What's needed of an auction to price it and to tell whether it sold
+#[derive(Clone, Copy, Debug)]
+pub struct Listing {
+    pub id: u64,
+    /// Its index in `Listings::variants`
+    variant: usize,
+    pub quantity: u16,
+    pub unit_price: Option<u64>,
+    /// See `Auction::price_per_unit`
+    pub price_per_unit: Option<u64>,
+    pub time_left: AuctionTime,
+}
+
+/// The listings of a snapshot
+#[derive(Debug, Default)]
+pub struct Listings {
+    pub market: Market,
+    /// When the API last updated the snapshot, from its `Last-Modified` header
+    pub last_modified: Option<DateTime<Utc>>,
+    /// The name the snapshot was archived under, e.g. `2020-09-15T09:31:02+00:00`, if it was
+    pub archived_as: Option<String>,
+    variants: Vec<Variant>,
+    variant_index: HashMap<Variant, usize>,
+    /// By auction id
+    listings: HashMap<u64, Listing>,
+    pet_species: HashSet<u32>,
+}
+
+impl Listings {
+    pub fn new(market: Market) -> Self {
+        Self {
+            market,
+            ..Default::default()
+        }
+    }
+
+    /// Take note of an auction
+    pub fn add(&mut self, a: &Auction) {
+        let key = (a.item.id, a.item.variant());
+        let variant = match self.variant_index.get(&key) {
+            Some(i) => *i,
+            None => {
+                self.variants.push(key.clone());
+                self.variant_index.insert(key, self.variants.len() - 1);
+                self.variants.len() - 1
+            }
+        };
+        if let Some(species) = a.item.pet_species_id {
+            self.pet_species.insert(species);
+        }
+        self.listings.insert(
+            a.id,
+            Listing {
+                id: a.id,
+                variant,
+                quantity: a.quantity,
+                unit_price: a.unit_price,
+                price_per_unit: a.price_per_unit(),
+                time_left: a.time_left,
+            },
+        );
+    }
+
+    /// How many auctions there were
+    pub fn len(&self) -> usize {
+        self.listings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listings.is_empty()
+    }
+
+    /// The listing of the auction, if it was in the snapshot
+    pub fn get(&self, auction_id: u64) -> Option<&Listing> {
+        self.listings.get(&auction_id)
+    }
+
+    /// The ids of the items listed
+    pub fn item_ids(&self) -> HashSet<u64> {
+        self.variants.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// The battle pet species caged
+    pub fn pet_species(&self) -> &HashSet<u32> {
+        &self.pet_species
+    }
+
+    /// The listings of each item variant, ordered by item id then variant
+    pub fn by_variant(&self) -> Vec<(&Variant, Vec<&Listing>)> {
+        let mut grouped = vec![vec![]; self.variants.len()];
+        for l in self.listings.values() {
+            grouped[l.variant].push(l);
+        }
+        let mut by_variant: Vec<(&Variant, Vec<&Listing>)> =
+            self.variants.iter().zip(grouped).collect();
+        by_variant.sort_by_key(|(v, _)| *v);
+        by_variant
+    }
+
+    /// The cheapest auction of each item variant with a unit price
+    pub fn best_auctions(&self) -> Vec<AuctionRow> {
+        self.by_variant()
+            .into_iter()
+            .filter_map(|((item_id, variant), ls)| {
+                let best = ls
+                    .into_iter()
+                    .filter(|l| l.unit_price.is_some())
+                    .min_by_key(|l| (l.unit_price, l.id))?;
+                Some(AuctionRow {
+                    market: self.market,
+                    item_id: *item_id,
+                    variant: variant.clone(),
+                    auction_id: best.id,
+                    quantity: best.quantity,
+                    unit_price: best.unit_price?,
+                })
+            })
+            .collect()
+    }
+
+    /// The price distribution of each item variant's listings
+    pub fn market_stats(&self) -> Vec<MarketStats> {
+        self.by_variant()
+            .into_iter()
+            .filter_map(|((item_id, variant), ls)| {
+                let prices = ls
+                    .iter()
+                    .filter_map(|l| l.price_per_unit.map(|p| (p, l.quantity as u64)))
+                    .collect();
+                MarketStats::from_prices(self.market, *item_id, variant.clone(), prices)
+            })
+            .collect()
+    }
+}
@@ -1,20 +1,31 @@
-use actix::{Actor, Addr, Arbiter, Context, Handler, Message, System};
-use chrono::{DateTime, Utc};
+use actix::{Actor, Addr};
+use chrono::{DateTime, Timelike, Utc};
 use clap::Clap;
-use glob::glob;
-use log::{error, info, trace};
-use lzma::{compress, decompress};
-use redis::Connection;
+use itertools::Itertools;
+use log::{error, info, trace, warn};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
-use std::io::Write;
-use tokio::stream::{self, StreamExt};
-use tokio::sync::mpsc::channel;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use tokio::time::{delay_for, Duration};
-use waw::actors::{AuctionRow, StorageActor, StoreAuction};
-use waw::db::dump_redis_proto;
-use waw::realm::{Auction, AuctionResponse, Realm};
-use waw::{get_session, Error, Opts, Settings, SubCmd};
+use waw::actors::{
+    MissingItems, MissingPets, StorageActor, StoreAuction, StoreItems, StorePets, StoreSales,
+    StoreSnapshot, StoreStats,
+};
+use waw::db::{dump_redis_proto, store_id, Backend};
+use waw::export::{partition, ParquetWriter, SeriesRow};
+use waw::ledger::Ledger;
+use waw::listings::Listings;
+use waw::realm::{
+    variant_key, Auction, AuctionResponse, Item, Market, MarketStats, Target, LOCALES,
+};
+use waw::sales::estimate_sales;
+use waw::snapshot::{archives, check_archive, prune, quarantine, read_archive, sha256, Manifest};
+use waw::{
+    ArchiveCmd, Error, ExportCmd, LoadFormat, LoadOpts, Opts, ParquetOpts, SeriesFormat,
+    SeriesOpts, SessionManager, Settings, SubCmd, VerifyOpts,
+};
 
 static COMPRESSED_DEPENDENCY_LIST: &[u8] = auditable::inject_dependency_list!();
 
@@ -24,147 +35,665 @@ fn main() -> Result<(), Error> {
     );
 
     info!("{}", COMPRESSED_DEPENDENCY_LIST[0]);
-    let _ = System::new("Waw");
-    let sa_addr = waw::actors::StorageActor::default().start();
     let settings = Settings::new()?;
     let opts = Opts::parse();
+    waw::migrate::migrate_archives(&settings.data_dir, settings.realm_id)?;
 
     match opts.cmd {
         SubCmd::Sync(sopts) => {
+            let store = if sopts.no_load {
+                None
+            } else if sopts.ephemeral {
+                Some(waw::db::open_store(&Settings {
+                    backend: Backend::Memory,
+                    ..settings.clone()
+                })?)
+            } else {
+                Some(waw::db::open_store(&settings)?)
+            };
             actix::run(async move {
+                let sa_addr = store.map(|s| StorageActor::new(s).start());
+                let sessions = SessionManager::new(settings.clone());
+                let mut previous: HashMap<Market, (Listings, i64)> = HashMap::new();
+                let mut known_items: HashSet<u64> = HashSet::new();
+                let mut known_pets: HashSet<u32> = HashSet::new();
+                let markets: Vec<Market> = Market::all(&settings.targets())
+                    .into_iter()
+                    .filter(|m| match m {
+                        Market::Commodities(_) => !sopts.no_commodities,
+                        Market::Realm(_) => true,
+                    })
+                    .collect();
                 loop {
-                    match download_auctions(settings.clone()).await {
-                        Err(e) => error!("Failed downloading auctions: {:?}", e),
-                        Ok((ar, ts_str)) => {
-                            let rfc3339 = DateTime::parse_from_rfc3339(&ts_str)
-                                .expect("Invalid date string from filename");
-                            info!("Download loop completed: {}", ts_str);
-
-                            if !sopts.no_load && ar.auctions.len() > 0 {
-                                for a in ar.best_auctions() {
-                                    let item_id = a.item_id.clone();
-                                    match sa_addr
-                                        .send(StoreAuction {
-                                            db_host: settings.db_host.clone(),
-                                            auction_row: a,
-                                            timestamp: rfc3339.timestamp(),
-                                        })
-                                        .await
-                                    {
-                                        Ok(sr) => {
-                                            trace!("Storage result for {}: {:?}", item_id, sr);
-                                        }
-                                        Err(e) => {
-                                            error!("Inbox full for {}: {}", item_id, e);
-                                        }
+                    for market in markets.iter() {
+                        match download_auctions(&sessions, &settings, *market).await {
+                            Err(e) => error!("Failed downloading {:?} auctions: {:?}", market, e),
+                            Ok(None) => info!("No new snapshot for {:?}", market),
+                            Ok(Some((listings, ts_str))) => {
+                                let ts = DateTime::parse_from_rfc3339(&ts_str)
+                                    .expect("Invalid date string from filename")
+                                    .timestamp();
+                                info!("Download loop completed: {:?} {}", market, ts_str);
+
+                                if let (Some(sa_addr), false) = (&sa_addr, listings.is_empty()) {
+                                    // Only the order book needs every auction, so it's read back
+                                    let book = if sopts.order_book {
+                                        order_book(&settings, *market, &ts_str)
+                                            .map_err(|e| error!("No order book: {:?}", e))
+                                            .ok()
+                                    } else {
+                                        None
                                     };
+                                    load_auctions(
+                                        sa_addr,
+                                        &listings,
+                                        previous.get(market),
+                                        ts,
+                                        book,
+                                    )
+                                    .await;
+                                    sync_items(
+                                        &sessions,
+                                        sa_addr,
+                                        &listings,
+                                        &mut known_items,
+                                        settings.item_fetch_limit(),
+                                    )
+                                    .await;
+                                    sync_pets(
+                                        &sessions,
+                                        sa_addr,
+                                        &listings,
+                                        &mut known_pets,
+                                        settings.pet_fetch_limit(),
+                                    )
+                                    .await;
                                 }
+                                previous.insert(*market, (listings, ts));
+                                info!("Finished: {:?} {}", market, ts_str);
                             }
-                            info!("Finished: {}", ts_str);
-                        }
-                    };
+                        };
+                    }
                     delay_for(Duration::from_secs(60 * settings.delay_mins)).await;
                 }
             })?;
         }
-        SubCmd::Load => {
+        SubCmd::Search(sopts) => {
+            if !LOCALES.contains(&sopts.locale.as_str()) {
+                return Err(Error::ConfigError(format!(
+                    "No such locale {}, expected one of {:?}",
+                    sopts.locale, LOCALES
+                )));
+            }
+            let mut store = waw::db::open_store(&settings)?;
+            for id in store.search_ids_for_item(&sopts.locale, &sopts.name)? {
+                if let Some(item) = store.get_item_metadata(id)? {
+                    println!("{}\t{}", item.id, item.name(&sopts.locale));
+                }
+            }
+        }
+        SubCmd::Load(lopts) => {
+            let sa_addr = match lopts.format {
+                LoadFormat::Store => Some(waw::db::open_store(&settings)?),
+                LoadFormat::Resp => None,
+            };
+            let mut ledger = match lopts.format {
+                LoadFormat::Store => Some(Ledger::open(
+                    &format!("{}/ledger.jsonl", settings.data_dir),
+                    &store_id(&settings),
+                )?),
+                LoadFormat::Resp => None,
+            };
             actix::run(async move {
-                info!(
-                    "Loading dir {} with {}",
-                    settings.data_dir, settings.db_host
-                );
-                let mut auc_stream = tokio::stream::iter(
-                    glob(&format!("{}/*.xz", settings.data_dir)).expect("Cannot glob data_dir"),
-                )
-                .filter_map(valid_path)
-                .map(parse_file);
-
-                let (mut tx, mut rx) = channel(100);
-
-                tokio::spawn(async move {
-                    loop {
-                        match auc_stream.next().await {
-                            Some(Ok((ar, ts))) => {
-                                info!("Received {}", ts);
-                                for auc in ar.auctions {
-                                    if let Err(_) = tx.send((auc, ts)).await {
-                                        error!("Receiver dropped: {:?}", ts);
-                                        return;
-                                    }
-                                }
-                                info!("Done {}", ts);
-                            }
-                            Some(Err(e)) => {
-                                error!("Failed parsing auction: {:?}", e);
-                                break;
-                            }
-                            None => {
-                                info!("Stream finished");
-                                break;
-                            }
-                        }
-                    }
-                });
-
-                while let Some((auc, ts)) = rx.recv().await {
-                    if auc.unit_price.is_some() {
-                        match dump_redis_proto(&auc, ts).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                error!("Failed to dump redis data: {}", e);
-                            }
-                        };
+                let sa_addr = sa_addr.map(|s| StorageActor::new(s).start());
+                for market in Market::all(&settings.targets()) {
+                    let store = sa_addr.as_ref().zip(ledger.as_mut());
+                    if let Err(e) = replay(&settings, &lopts, market, store).await {
+                        error!("Failed replaying {:?} snapshots: {:?}", market, e);
                     }
                 }
             })?;
         }
+        SubCmd::Export(eopts) => match eopts.cmd {
+            ExportCmd::Parquet(popts) => export_parquet(&settings, &popts)?,
+            ExportCmd::Series(sopts) => export_series(&settings, &sopts)?,
+        },
+        SubCmd::Archive(aopts) => match aopts.cmd {
+            ArchiveCmd::Verify(vopts) => verify(&settings, &vopts)?,
+            ArchiveCmd::Prune(popts) => {
+                for market in Market::all(&settings.targets()) {
+                    for file in prune_archives(&settings, market, popts.dry_run)? {
+                        println!("{}", file.display());
+                    }
+                }
+            }
+        },
     }
     Ok(())
 }
 
-fn valid_path(f: Result<std::path::PathBuf, glob::GlobError>) -> Option<std::path::PathBuf> {
-    match f {
-        Ok(path) => Some(path),
-        Err(e) => {
-            error!("Error globbing {:?}", e);
+/// Prune the market's archives according to the settings
+fn prune_archives(
+    settings: &Settings,
+    market: Market,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    prune(
+        &market.archive_dir(&settings.data_dir),
+        settings.retention.as_ref(),
+        settings.archive_json.unwrap_or(true),
+        dry_run,
+    )
+}
+
+/// Check every market's archives against their manifests, failing if any are corrupt
+fn verify(settings: &Settings, vopts: &VerifyOpts) -> Result<(), Error> {
+    let dictionary = settings.zstd_dictionary()?;
+    let dictionary = dictionary.as_deref();
+    let (mut checked, mut corrupt, mut unlisted) = (0, 0, 0);
+    for market in Market::all(&settings.targets()) {
+        for (_, path) in archives(&market.archive_dir(&settings.data_dir))? {
+            checked += 1;
+            let res = sha256(&path)
+                .and_then(|sha| check_archive(&path, &sha, dictionary))
+                .and_then(|_| Manifest::read(&path));
+            match res {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    warn!("{} has no manifest", path.display());
+                    unlisted += 1;
+                }
+                Err(e) => {
+                    println!("{}: {:?}", path.display(), e);
+                    corrupt += 1;
+                    if vopts.quarantine {
+                        quarantine(&path)?;
+                    }
+                }
+            }
+        }
+    }
+    info!(
+        "Verified {} archives, {} corrupt and {} without a manifest",
+        checked, corrupt, unlisted
+    );
+    if corrupt > 0 && !vopts.quarantine {
+        return Err(Error::CorruptArchive(format!(
+            "{} of {} archives",
+            corrupt, checked
+        )));
+    }
+    Ok(())
+}
+
+/// Replay the market's archived snapshots within the window, oldest first, into the store or as
+/// Redis protocol on stdout. Re-running overwrites the points stored the first time.
+///
+/// Snapshots the ledger has as loaded into the store, with the same contents, are skipped unless
+/// `--force` is given, and each one loaded is added to it.
+async fn replay(
+    settings: &Settings,
+    lopts: &LoadOpts,
+    market: Market,
+    mut store: Option<(&Addr<StorageActor>, &mut Ledger)>,
+) -> Result<(), Error> {
+    let dir = market.archive_dir(&settings.data_dir);
+    let archives = archives(&dir)?;
+    let dictionary = settings.zstd_dictionary()?;
+    let dictionary = dictionary.as_deref();
+    let range = window(&archives, lopts.since, lopts.until);
+    let (before, window) = (range.start, range.len());
+    info!("Replaying {} snapshots from {}", window, dir);
+
+    let mut previous: Option<(Listings, i64)> = None;
+    let mut skipped = 0;
+    for i in before..before + window {
+        let (ts, path) = &archives[i];
+        let ts = ts.timestamp();
+        let sha = match sha256(path) {
+            Ok(sha) => sha,
+            Err(e) => {
+                error!("Skipping {}: {:?}", path.display(), e);
+                previous = None;
+                continue;
+            }
+        };
+        let (sa_addr, ledger) = match store.as_mut() {
+            Some((sa_addr, ledger)) => (*sa_addr, ledger),
+            None => {
+                match check_file(market, path, &sha, dictionary) {
+                    Ok(ar) => {
+                        for row in ar.best_auctions() {
+                            print!("{}", dump_redis_proto(&row, ts));
+                        }
+                    }
+                    Err(e) => error!("Skipping {}: {:?}", path.display(), e),
+                }
+                continue;
+            }
+        };
+        let file = path
+            .strip_prefix(&settings.data_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned();
+        if !lopts.force && ledger.is_loaded(&file, &sha) {
+            skipped += 1;
+            previous = None;
+            continue;
+        }
+        // The snapshot before, so sales can be estimated, unless it was just loaded
+        if previous.is_none() && i > 0 {
+            let (prev_ts, prev_path) = &archives[i - 1];
+            previous = parse_file(market, prev_path, dictionary)
+                .map_err(|e| warn!("No sales for {}: {:?}", path.display(), e))
+                .ok()
+                .map(|ar| (ar.listings(), prev_ts.timestamp()));
+        }
+        let ar = match check_file(market, path, &sha, dictionary) {
+            Ok(ar) => ar,
+            Err(e) => {
+                error!("Skipping {}: {:?}", path.display(), e);
+                previous = None;
+                continue;
+            }
+        };
+        let listings = ar.listings();
+        let book = if lopts.order_book {
+            Some(ar.auctions)
+        } else {
             None
+        };
+        load_auctions(sa_addr, &listings, previous.as_ref(), ts, book).await;
+        ledger.record(&file, &sha)?;
+        info!(
+            "Replayed {}/{} {:?} snapshots, up to {}",
+            i - before + 1,
+            window,
+            market,
+            path.display()
+        );
+        previous = Some((listings, ts));
+    }
+    if skipped > 0 {
+        info!(
+            "Skipped {} {:?} snapshots already loaded, use --force to load them again",
+            skipped, market
+        );
+    }
+    Ok(())
+}
+
+/// The range of the archives, as from `archives`, taken at or after `since` and before `until`
+fn window(
+    archives: &[(DateTime<Utc>, PathBuf)],
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> Range<usize> {
+    let before = archives
+        .iter()
+        .take_while(|(ts, _)| matches!(since, Some(since) if *ts < since))
+        .count();
+    let window = archives[before..]
+        .iter()
+        .take_while(|(ts, _)| !matches!(until, Some(until) if *ts >= until))
+        .count();
+    before..before + window
+}
+
+/// The window widened to the start of `since`'s day and the end of `until`'s
+fn whole_days(
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let midnight = |t: DateTime<Utc>| {
+        t - chrono::Duration::seconds(t.num_seconds_from_midnight().into())
+            - chrono::Duration::nanoseconds(t.nanosecond().into())
+    };
+    let since = since.map(midnight);
+    let until = until.map(|u| match midnight(u) {
+        start if start == u => u,
+        start => start + chrono::Duration::days(1),
+    });
+    (since, until)
+}
+
+/// Export the archived auctions in the window to Parquet, a file for each market and day.
+///
+/// Each day's file is rewritten whole, so the window is widened to whole days.
+fn export_parquet(settings: &Settings, popts: &ParquetOpts) -> Result<(), Error> {
+    let out = popts
+        .out
+        .clone()
+        .unwrap_or_else(|| format!("{}/parquet", settings.data_dir));
+    let dictionary = settings.zstd_dictionary()?;
+    let (since, until) = whole_days(popts.since, popts.until);
+    for market in Market::all(&settings.targets()) {
+        let archives = archives(&market.archive_dir(&settings.data_dir))?;
+        let range = window(&archives, since, until);
+        let days = archives[range]
+            .iter()
+            .group_by(|(ts, _)| ts.naive_utc().date());
+        for (day, snapshots) in days.into_iter() {
+            let path = partition(out.as_ref(), market, day).join("auctions.parquet");
+            let mut writer = ParquetWriter::create(&path)?;
+            let mut rows = 0;
+            for (ts, archive) in snapshots {
+                match parse_file(market, archive, dictionary.as_deref()) {
+                    Ok(ar) => rows += writer.write(&ar, *ts)?,
+                    Err(e) => error!("Skipping {}: {:?}", archive.display(), e),
+                }
+            }
+            writer.finish()?;
+            info!("Exported {} auctions to {}", rows, path.display());
         }
     }
+    Ok(())
+}
+
+/// Write the items' series, from the store, as CSV or newline-delimited JSON
+fn export_series(settings: &Settings, sopts: &SeriesOpts) -> Result<(), Error> {
+    if let Some(stat) = sopts.stat.as_deref() {
+        if !MarketStats::NAMES.contains(&stat) {
+            return Err(Error::ConfigError(format!("No such statistic {}", stat)));
+        }
+    }
+    let target = settings.targets().first().copied().unwrap_or_default();
+    let target = Target {
+        realm_id: sopts.realm.unwrap_or(target.realm_id),
+        ..target
+    };
+    let market = if sopts.commodities {
+        Market::Commodities(target.region)
+    } else {
+        Market::Realm(target)
+    };
+    let mut store = waw::db::open_store(settings)?;
+    let mut rows = vec![];
+    for name in sopts.items.iter() {
+        // Series can be stored before an item's metadata is, so ids needn't have any
+        let item = match name.parse::<u64>() {
+            Ok(id) => Some(store.get_item_metadata(id)?.unwrap_or(Item {
+                id,
+                ..Default::default()
+            })),
+            Err(_) => store.get_item_metadata_by_name(&sopts.locale, name)?,
+        }
+        .ok_or_else(|| Error::ConfigError(format!("No item named {}", name)))?;
+        let mut key = format!(
+            "auc:{}:{}",
+            market.namespace(),
+            variant_key(item.id, sopts.variant.as_deref())
+        );
+        if let Some(stat) = sopts.stat.as_deref() {
+            key = format!("{}:{}", key, stat);
+        }
+        let points: Vec<(i64, u64)> = store
+            .get_range(&key)?
+            .into_iter()
+            .filter(|(ts, _)| !matches!(sopts.since, Some(since) if *ts < since.timestamp()))
+            .filter(|(ts, _)| !matches!(sopts.until, Some(until) if *ts >= until.timestamp()))
+            .collect();
+        let points = match sopts.bucket {
+            Some(bucket) => sopts.aggregation.apply(&points, bucket),
+            None => points,
+        };
+        info!("Exporting {} points of {}", points.len(), key);
+        rows.extend(points.into_iter().map(|(ts, value)| SeriesRow {
+            item: item.id,
+            name: item.name(&sopts.locale).to_string(),
+            variant: sopts.variant.clone(),
+            ts,
+            value,
+        }));
+    }
+
+    let out: Box<dyn Write> = match sopts.out.as_deref() {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    match sopts.format {
+        SeriesFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(out);
+            for row in rows {
+                csv.serialize(row)?;
+            }
+            csv.flush()?;
+        }
+        SeriesFormat::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut out, &row)?;
+                out.write_all(b"\n")?;
+            }
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Read an archive, checking it against its manifest, and quarantine it if it's corrupt
+fn check_file(
+    market: Market,
+    p: &Path,
+    sha: &str,
+    dictionary: Option<&[u8]>,
+) -> Result<AuctionResponse, Error> {
+    info!("Loading {:?}", p.display());
+    let mut ar = match check_archive(p, sha, dictionary) {
+        Err(e @ Error::CorruptArchive(_)) => {
+            quarantine(p)?;
+            return Err(e);
+        }
+        res => res?,
+    };
+    ar.market = market;
+    Ok(ar)
+}
+
+fn parse_file(
+    market: Market,
+    p: &Path,
+    dictionary: Option<&[u8]>,
+) -> Result<AuctionResponse, Error> {
+    info!("Loading {:?}", p.display());
+    let mut ar = read_archive(p, dictionary)?;
+    ar.market = market;
+    Ok(ar)
+}
+
+/// Store the best prices, market statistics, with a `previous` snapshot sales estimates, and
+/// given every auction the order book
+async fn load_auctions(
+    sa_addr: &Addr<StorageActor>,
+    listings: &Listings,
+    previous: Option<&(Listings, i64)>,
+    ts: i64,
+    book: Option<Vec<Auction>>,
+) {
+    for a in listings.best_auctions() {
+        let item_id = a.item_id;
+        match sa_addr
+            .send(StoreAuction {
+                auction_row: a,
+                timestamp: ts,
+            })
+            .await
+        {
+            Ok(sr) => {
+                trace!("Storage result for {}: {:?}", item_id, sr);
+            }
+            Err(e) => {
+                error!("Inbox full for {}: {}", item_id, e);
+            }
+        };
+    }
+    let sr = sa_addr
+        .send(StoreStats {
+            stats: listings.market_stats(),
+            timestamp: ts,
+        })
+        .await;
+    trace!("Stats storage result for {}: {:?}", ts, sr);
+    if let Some((prev, prev_ts)) = previous {
+        let elapsed = chrono::Duration::seconds(ts - prev_ts);
+        let sr = sa_addr
+            .send(StoreSales {
+                sales: estimate_sales(prev, listings, elapsed),
+                timestamp: ts,
+            })
+            .await;
+        trace!("Sales storage result for {}: {:?}", ts, sr);
+    }
+    if let Some(auctions) = book {
+        let sr = sa_addr
+            .send(StoreSnapshot {
+                market: listings.market,
+                auctions,
+                timestamp: ts,
+            })
+            .await;
+        trace!("Order book storage result for {}: {:?}", ts, sr);
+    }
 }
 
-fn parse_file(p: std::path::PathBuf) -> Result<(AuctionResponse, i64), Error> {
-    info!("Loading {:?}", p.clone().display());
-    let in_file = File::open(p.clone()).expect("Could not read auction file");
-
-    let rfc3339 = DateTime::parse_from_rfc3339(p.file_stem().unwrap().to_str().unwrap())
-        .expect("Invalid date string from filename");
-    let mut s = String::new();
-    let mut r = lzma::LzmaReader::new_decompressor(in_file)?;
-    r.read_to_string(&mut s)?;
-    Ok((
-        serde_json::from_str(&s).expect(&format!("Failed to read JSON for {:?}", p)),
-        rfc3339.timestamp(),
-    ))
+/// Fetch and store the metadata of items in the snapshot that the store has none for, up to
+/// `limit` of them. `known` remembers the items already dealt with between snapshots.
+async fn sync_items(
+    sessions: &SessionManager,
+    sa_addr: &Addr<StorageActor>,
+    listings: &Listings,
+    known: &mut HashSet<u64>,
+    limit: usize,
+) {
+    let unseen: Vec<u64> = listings
+        .item_ids()
+        .into_iter()
+        .filter(|id| !known.contains(id))
+        .collect();
+    if unseen.is_empty() {
+        return;
+    }
+    let missing = match sa_addr.send(MissingItems(unseen.clone())).await {
+        Ok(Ok(missing)) => missing,
+        Ok(Err(e)) => {
+            error!("Failed to find missing items: {:?}", e);
+            return;
+        }
+        Err(e) => {
+            error!("Inbox full when finding missing items: {}", e);
+            return;
+        }
+    };
+    known.extend(unseen.into_iter().filter(|id| !missing.contains(id)));
+
+    let mut items = vec![];
+    for id in missing.into_iter().take(limit) {
+        match sessions.item(listings.market.region(), id).await {
+            Ok(Some(item)) => items.push(item),
+            Ok(None) => warn!("No metadata for item {}", id),
+            Err(e) => {
+                error!("Failed fetching item {}: {:?}", id, e);
+                continue;
+            }
+        }
+        known.insert(id);
+    }
+    info!("Fetched metadata for {} items", items.len());
+    if !items.is_empty() {
+        let sr = sa_addr.send(StoreItems(items)).await;
+        trace!("Item storage result: {:?}", sr);
+    }
 }
 
-async fn download_auctions(settings: Settings) -> Result<(AuctionResponse, String), Error> {
-    let session = get_session(settings.clone())
-        .await
-        .expect("Failed to authenticate");
-    info!("Loading auctions");
-    let auc = session.auctions().await?;
-    let ts = archive_auctions(settings.data_dir.clone().to_string(), &auc).await?;
-    Ok((auc, ts))
+/// Fetch and store the names of up to `limit` battle pet species caged in the snapshot that
+/// aren't already stored
+async fn sync_pets(
+    sessions: &SessionManager,
+    sa_addr: &Addr<StorageActor>,
+    listings: &Listings,
+    known: &mut HashSet<u32>,
+    limit: usize,
+) {
+    let unseen: Vec<u32> = listings
+        .pet_species()
+        .iter()
+        .copied()
+        .filter(|id| !known.contains(id))
+        .collect();
+    if unseen.is_empty() {
+        return;
+    }
+    let missing = match sa_addr.send(MissingPets(unseen.clone())).await {
+        Ok(Ok(missing)) => missing,
+        Ok(Err(e)) => {
+            error!("Failed to find missing pet species: {:?}", e);
+            return;
+        }
+        Err(e) => {
+            error!("Inbox full when finding missing pet species: {}", e);
+            return;
+        }
+    };
+    known.extend(unseen.into_iter().filter(|id| !missing.contains(id)));
+
+    let mut pets = vec![];
+    for id in missing.into_iter().take(limit) {
+        match sessions.pet(listings.market.region(), id).await {
+            Ok(Some(pet)) => pets.push(pet),
+            Ok(None) => warn!("No such pet species {}", id),
+            Err(e) => {
+                error!("Failed fetching pet species {}: {:?}", id, e);
+                continue;
+            }
+        }
+        known.insert(id);
+    }
+    info!("Fetched names for {} pet species", pets.len());
+    if !pets.is_empty() {
+        let sr = sa_addr.send(StorePets(pets)).await;
+        trace!("Pet species storage result: {:?}", sr);
+    }
 }
 
-async fn archive_auctions(data_dir: String, auc: &AuctionResponse) -> Result<String, Error> {
-    info!("Saving auctions to {:?}", data_dir);
-    let timestamp = Utc::now().format("%+");
-    let json = File::create(format!("{}/{}.json", data_dir, timestamp.to_string()))?;
-    serde_json::to_writer(json, auc)?;
+/// Fetch and archive the market's auctions, unless there's been no update since the last fetch
+async fn download_auctions(
+    sessions: &SessionManager,
+    settings: &Settings,
+    market: Market,
+) -> Result<Option<(Listings, String)>, Error> {
+    info!(
+        "Loading {:?} auctions into {}",
+        market,
+        market.archive_dir(&settings.data_dir)
+    );
+    match sessions.auctions(market).await? {
+        Some(auc) => {
+            let ts = auc
+                .archived_as
+                .clone()
+                .ok_or_else(|| Error::IOError(format!("{:?} snapshot wasn't archived", market)))?;
+            if matches!(&settings.retention, Some(r) if r.prune_on_sync) {
+                if let Err(e) = prune_archives(settings, market, false) {
+                    error!("Failed pruning {:?} archives: {:?}", market, e);
+                }
+            }
+            Ok(Some((auc, ts)))
+        }
+        None => Ok(None),
+    }
+}
 
-    let mut compressed = File::create(format!("{}/{}.xz", data_dir, timestamp.to_string()))?;
-    compressed.write_all(&compress(&serde_json::to_vec(auc)?, 9).expect("Failed to compress"))?;
-    info!("Auctions saved {}", timestamp.to_string());
-    Ok(timestamp.to_string())
+/// Every auction of the market's snapshot archived as `archived_as`, read back from the archive
+fn order_book(
+    settings: &Settings,
+    market: Market,
+    archived_as: &str,
+) -> Result<Vec<Auction>, Error> {
+    let path = Path::new(&market.archive_dir(&settings.data_dir)).join(format!(
+        "{}.{}",
+        archived_as,
+        settings.archive_codec.extension()
+    ));
+    Ok(read_archive(&path, settings.zstd_dictionary()?.as_deref())?.auctions)
 }
@@ -0,0 +1,122 @@
+//! Moves what was written before several realms could be synced, when the one EU realm was
+//! archived straight into `data_dir` and its series keyed as `auc:item:{id}`.
+use crate::db::PriceStore;
+use crate::realm::{Market, Region, Target};
+use crate::Error;
+use chrono::DateTime;
+use log::{info, warn};
+use std::path::Path;
+
+/// The flag set once a store's series have been moved into their market's namespace
+const SERIES_NAMESPACED: &str = "series_namespaced";
+
+/// The realm archived before there were markets, the `realm_id` setting being the only way to
+/// configure one
+fn legacy_market(realm_id: Option<u16>) -> Option<Market> {
+    realm_id.map(|realm_id| {
+        Market::Realm(Target {
+            region: Region::Eu,
+            realm_id,
+        })
+    })
+}
+
+/// Whether the file is an archived snapshot, or its JSON, named for when it was taken
+fn is_snapshot(path: &Path) -> bool {
+    let archive =
+        path.extension() == Some("xz".as_ref()) || path.extension() == Some("json".as_ref());
+    archive
+        && path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .is_some()
+}
+
+/// Move snapshots from the root of `data_dir` into the realm's own directory, returning how many
+/// files were moved
+pub fn migrate_archives(data_dir: &str, realm_id: Option<u16>) -> Result<usize, Error> {
+    let market = match legacy_market(realm_id) {
+        Some(market) => market,
+        None => return Ok(0),
+    };
+    let entries = match std::fs::read_dir(data_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let to = market.archive_dir(data_dir);
+    let mut moved = 0;
+    for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+        if !path.is_file() || !is_snapshot(&path) {
+            continue;
+        }
+        let dest = Path::new(&to).join(path.file_name().unwrap_or_default());
+        if dest.exists() {
+            warn!("Not moving {}, {} exists", path.display(), dest.display());
+            continue;
+        }
+        std::fs::create_dir_all(&to)?;
+        std::fs::rename(&path, &dest)?;
+        moved += 1;
+    }
+    if moved > 0 {
+        info!("Moved {} legacy snapshot files into {}", moved, to);
+    }
+    Ok(moved)
+}
+
+/// Rename the store's legacy series into the realm's namespace, once
+pub fn migrate_series(store: &mut dyn PriceStore, realm_id: Option<u16>) -> Result<(), Error> {
+    if store.has_flag(SERIES_NAMESPACED)? {
+        return Ok(());
+    }
+    if let Some(market) = legacy_market(realm_id) {
+        store.rename_series("auc:item:", &format!("auc:{}:", market.namespace()))?;
+    }
+    store.set_flag(SERIES_NAMESPACED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::MemoryStore;
+
+    #[test]
+    fn moves_legacy_archives_and_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let snapshot = "2020-09-15T10:00:00.123+00:00";
+        for file in &[
+            format!("{}.xz", snapshot),
+            format!("{}.json", snapshot),
+            "ledger.jsonl".to_string(),
+        ] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        assert_eq!(migrate_archives(data_dir, Some(1403)).unwrap(), 2);
+        assert!(dir.path().join("ledger.jsonl").exists());
+        for file in &[
+            format!("eu/1403/{}.xz", snapshot),
+            format!("eu/1403/{}.json", snapshot),
+        ] {
+            assert!(dir.path().join(file).exists(), "{} not moved", file);
+        }
+        assert_eq!(migrate_archives(data_dir, Some(1403)).unwrap(), 0);
+
+        let mut store = MemoryStore::default();
+        store.store_point("auc:item:109119", 1, 100).unwrap();
+        migrate_series(&mut store, Some(1403)).unwrap();
+        assert!(store.get_range("auc:item:109119").unwrap().is_empty());
+        assert_eq!(
+            store.get_range("auc:eu:1403:item:109119").unwrap(),
+            vec![(1, 100)]
+        );
+
+        // Only once, so anything keyed the old way since is left alone
+        store.store_point("auc:item:109119", 2, 100).unwrap();
+        migrate_series(&mut store, Some(1403)).unwrap();
+        assert_eq!(store.get_range("auc:item:109119").unwrap(), vec![(2, 100)]);
+    }
+}
@@ -1,32 +1,332 @@
+use crate::http::Api;
+use crate::listings::Listings;
+use crate::snapshot::{ArchiveTo, Archiver, SnapshotParser};
 use crate::AsKey;
 use crate::{Error, Session};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 
 /// A WoW realm
+///
+/// Each look-up takes the time of the snapshot already held, if any, and yields `None` when
+/// the API has nothing newer. Snapshots are parsed as they download and, given somewhere to
+/// `archive` them, archived there as they arrive.
 #[async_trait]
 pub trait Realm {
-    async fn auctions(&self) -> Result<AuctionResponse, Error>;
+    /// The given connected realm's own auction house
+    async fn auctions(
+        &self,
+        realm_id: u16,
+        since: Option<DateTime<Utc>>,
+        archive: Option<ArchiveTo<'_>>,
+    ) -> Result<Option<Listings>, Error>;
+
+    /// The region-wide commodities auction house, where stackable items are sold
+    async fn commodities(
+        &self,
+        since: Option<DateTime<Utc>>,
+        archive: Option<ArchiveTo<'_>>,
+    ) -> Result<Option<Listings>, Error>;
+
+    /// An item's metadata, or `None` if there's no such item
+    async fn item(&self, id: u64) -> Result<Option<Item>, Error>;
+
+    /// A battle pet species' names, or `None` if there's no such species
+    async fn pet(&self, species_id: u32) -> Result<Option<PetSpecies>, Error>;
+
+    /// The auctions listed in the given market
+    async fn market(
+        &self,
+        market: Market,
+        since: Option<DateTime<Utc>>,
+        archive: Option<ArchiveTo<'_>>,
+    ) -> Result<Option<Listings>, Error> {
+        match market {
+            Market::Realm(target) => self.auctions(target.realm_id, since, archive).await,
+            Market::Commodities(_) => self.commodities(since, archive).await,
+        }
+    }
 }
 
 #[async_trait]
 impl Realm for Session {
-    async fn auctions(&self) -> Result<AuctionResponse, Error> {
-        let aurl = &self.auction_url();
-        let res = reqwest::get(aurl).await?;
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let ahd: AuctionResponse = res.json().await?;
-                info!("{:?}", ahd.auctions.len());
-                Ok(ahd)
+    async fn auctions(
+        &self,
+        realm_id: u16,
+        since: Option<DateTime<Utc>>,
+        archive: Option<ArchiveTo<'_>>,
+    ) -> Result<Option<Listings>, Error> {
+        let target = Target {
+            region: self.region,
+            realm_id,
+        };
+        fetch_auctions(
+            &self.api,
+            &self.auth.access_token,
+            &self.auction_url(realm_id),
+            Market::Realm(target),
+            since,
+            archive,
+        )
+        .await
+    }
+
+    async fn commodities(
+        &self,
+        since: Option<DateTime<Utc>>,
+        archive: Option<ArchiveTo<'_>>,
+    ) -> Result<Option<Listings>, Error> {
+        fetch_auctions(
+            &self.api,
+            &self.auth.access_token,
+            &self.commodities_url(),
+            Market::Commodities(self.region),
+            since,
+            archive,
+        )
+        .await
+    }
+
+    async fn item(&self, id: u64) -> Result<Option<Item>, Error> {
+        let item: Option<ItemResponse> = fetch_document(
+            &self.api,
+            &self.auth.access_token,
+            &self.item_url(id),
+            &format!("Item {}", id),
+        )
+        .await?;
+        Ok(item.map(Item::from))
+    }
+
+    async fn pet(&self, species_id: u32) -> Result<Option<PetSpecies>, Error> {
+        let url = self.pet_url(species_id);
+        let pet: Option<PetResponse> = fetch_document(
+            &self.api,
+            &self.auth.access_token,
+            &url,
+            &format!("Pet species {}", species_id),
+        )
+        .await?;
+        Ok(pet.map(PetSpecies::from))
+    }
+}
+
+/// A static Game Data API document, `None` if it doesn't exist
+async fn fetch_document<T: serde::de::DeserializeOwned>(
+    api: &Api,
+    token: &str,
+    url: &str,
+    what: &str,
+) -> Result<Option<T>, Error> {
+    let res = api
+        .send(|client| client.get(url).bearer_auth(token))
+        .await?;
+    match res.status() {
+        reqwest::StatusCode::OK => Ok(Some(res.json::<T>().await?)),
+        reqwest::StatusCode::NOT_FOUND => Ok(None),
+        reqwest::StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+        sc => {
+            info!("Unexpected response status code: {:?}", sc);
+            Err(Error::ApiFailure(format!(
+                "{} look-up failed: {}",
+                what, sc
+            )))
+        }
+    }
+}
+
+async fn fetch_auctions(
+    api: &Api,
+    token: &str,
+    url: &str,
+    market: Market,
+    since: Option<DateTime<Utc>>,
+    archive: Option<ArchiveTo<'_>>,
+) -> Result<Option<Listings>, Error> {
+    let mut res = api
+        .send(|client| {
+            let req = client.get(url).bearer_auth(token);
+            match since {
+                Some(since) => req.header(reqwest::header::IF_MODIFIED_SINCE, http_date(since)),
+                None => req,
             }
-            sc => {
-                info!("Unexpected response status code: {:?}", sc);
-                Err(Error::AuctionLookup("Auction look-up failed"))
+        })
+        .await?;
+    match res.status() {
+        reqwest::StatusCode::OK => {
+            let last_modified = res
+                .headers()
+                .get(reqwest::header::LAST_MODIFIED)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_http_date);
+            // Not every server honours If-Modified-Since, so check before reading or archiving
+            if matches!((last_modified, since), (Some(lm), Some(s)) if lm <= s) {
+                info!("{:?} unchanged since {:?}", market, since);
+                return Ok(None);
             }
+            // Name the snapshot for when the API updated it, rather than when it was fetched
+            let archived_as = last_modified
+                .unwrap_or_else(Utc::now)
+                .format("%+")
+                .to_string();
+            let archiver = match archive {
+                Some(to) => Some(Archiver::create(to, &archived_as, market, last_modified)?),
+                None => None,
+            };
+            let parser = SnapshotParser::spawn(market, archiver);
+            let downloaded = async {
+                while let Some(chunk) = res.chunk().await? {
+                    // A parser that's stopped has failed, which `finish` reports
+                    if !parser.send(chunk.to_vec()).await? {
+                        break;
+                    }
+                }
+                Ok::<_, Error>(())
+            }
+            .await;
+            // Finish either way, so a failed download's partial archive is cleaned up
+            let parsed = parser.finish().await;
+            let mut listings = match (downloaded, parsed) {
+                (Err(e), _) | (Ok(_), Err(e)) => return Err(e),
+                (Ok(_), Ok(listings)) => listings,
+            };
+            info!("{:?} {:?} {:?}", market, listings.len(), last_modified);
+            listings.last_modified = last_modified;
+            listings.archived_as = archive.map(|_| archived_as);
+            Ok(Some(listings))
+        }
+        reqwest::StatusCode::NOT_MODIFIED => {
+            info!("{:?} unchanged since {:?}", market, since);
+            Ok(None)
+        }
+        reqwest::StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+        sc => {
+            info!("Unexpected response status code: {:?}", sc);
+            Err(Error::AuctionLookup("Auction look-up failed"))
+        }
+    }
+}
+
+/// Format a time as an HTTP date, e.g. `Tue, 15 Sep 2020 09:31:02 GMT`
+pub fn http_date(t: DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parse an HTTP date such as a `Last-Modified` header
+pub fn parse_http_date(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(s)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// A battle.net API region
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    Eu,
+    Us,
+    Kr,
+    Tw,
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Region::Eu
+    }
+}
+
+impl Region {
+    /// The short name used in hosts, namespaces and keys, e.g. `eu`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Region::Eu => "eu",
+            Region::Us => "us",
+            Region::Kr => "kr",
+            Region::Tw => "tw",
+        }
+    }
+
+    /// The host serving the Game Data APIs
+    pub fn api_host(&self) -> String {
+        format!("{}.api.blizzard.com", self.as_str())
+    }
+
+    /// The host issuing OAuth tokens; Korea and Taiwan share the APAC one
+    pub fn oauth_host(&self) -> &'static str {
+        match self {
+            Region::Eu => "eu.battle.net",
+            Region::Us => "us.battle.net",
+            Region::Kr | Region::Tw => "apac.battle.net",
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A connected realm to sync
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Target {
+    pub region: Region,
+    /// The connected realm id, e.g. 1403 = Draenor
+    pub realm_id: u16,
+}
+
+/// Which auction house a snapshot was taken from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Market {
+    /// A connected realm's auction house
+    Realm(Target),
+    /// A region-wide commodities auction house
+    Commodities(Region),
+}
+
+impl Default for Market {
+    fn default() -> Self {
+        Market::Realm(Target::default())
+    }
+}
+
+impl Market {
+    /// Every market for the targets, i.e. each realm and each of their regions' commodities
+    pub fn all(targets: &[Target]) -> Vec<Market> {
+        let realms = targets.iter().map(|t| Market::Realm(*t));
+        let regions = targets
+            .iter()
+            .map(|t| Market::Commodities(t.region))
+            .unique();
+        realms.chain(regions).collect()
+    }
+
+    pub fn region(&self) -> Region {
+        match self {
+            Market::Realm(t) => t.region,
+            Market::Commodities(r) => *r,
+        }
+    }
+
+    /// The key segment that keeps this market's series apart from the others
+    pub fn namespace(&self) -> String {
+        match self {
+            Market::Realm(t) => format!("{}:{}:item", t.region, t.realm_id),
+            Market::Commodities(r) => format!("{}:commodity", r),
+        }
+    }
+
+    /// The directory this market's snapshots are archived in
+    pub fn archive_dir(&self, data_dir: &str) -> String {
+        match self {
+            Market::Realm(t) => format!("{}/{}/{}", data_dir, t.region, t.realm_id),
+            Market::Commodities(r) => format!("{}/{}/commodities", data_dir, r),
         }
     }
 }
@@ -40,33 +340,154 @@ pub struct ConnectedRealmLink {
 /// The parent type for all auctions
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct AuctionResponse {
-    connected_realm: ConnectedRealmLink,
+    /// Absent for commodities, which aren't tied to a realm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connected_realm: Option<ConnectedRealmLink>,
     pub auctions: Vec<Auction>,
+    /// Where these auctions were listed; not part of the API response
+    #[serde(skip)]
+    pub market: Market,
+    /// When the API last updated these auctions, from its `Last-Modified` header
+    #[serde(skip)]
+    pub last_modified: Option<DateTime<Utc>>,
+    /// The name the snapshot was archived under, e.g. `2020-09-15T09:31:02+00:00`, if it was
+    #[serde(skip)]
+    pub archived_as: Option<String>,
 }
 
 impl AuctionResponse {
-    /// List the auctions by their lowest price
+    /// An empty response, for the auctions to be added to
+    pub fn new(connected_realm: Option<ConnectedRealmLink>) -> Self {
+        Self {
+            connected_realm,
+            auctions: vec![],
+            market: Market::default(),
+            last_modified: None,
+            archived_as: None,
+        }
+    }
+
+    /// The auctions' `Listings`
+    pub fn listings(&self) -> Listings {
+        let mut listings = Listings::new(self.market);
+        listings.last_modified = self.last_modified;
+        listings.archived_as = self.archived_as.clone();
+        for a in self.auctions.iter() {
+            listings.add(a);
+        }
+        listings
+    }
+
+    /// List the auctions by their lowest price, one per item variant
     pub fn best_auctions(&self) -> Vec<crate::actors::AuctionRow> {
-        self.auctions
+        self.listings().best_auctions()
+    }
+
+    /// The price distribution of each item variant's listings
+    pub fn market_stats(&self) -> Vec<MarketStats> {
+        self.listings().market_stats()
+    }
+}
+
+/// Summary of the listings for an item within one snapshot. Percentiles and the mean are
+/// weighted by quantity, so a stack of 200 counts 200 times.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MarketStats {
+    pub market: Market,
+    pub item_id: u64,
+    /// See `ItemIden::variant`
+    pub variant: Option<String>,
+    pub min: u64,
+    pub p10: u64,
+    pub p25: u64,
+    pub median: u64,
+    pub p75: u64,
+    pub mean: u64,
+    /// Total units on offer
+    pub quantity: u64,
+    /// Number of auctions
+    pub listings: u64,
+}
+
+impl MarketStats {
+    /// The names of each statistic, as used in series keys
+    pub const NAMES: [&'static str; 8] = [
+        "min", "p10", "p25", "median", "p75", "mean", "quantity", "listings",
+    ];
+
+    /// Summarise the `(price per unit, quantity)` of each of the auctions for `item_id` that has
+    /// a buyout price
+    pub fn from_prices(
+        market: Market,
+        item_id: u64,
+        variant: Option<String>,
+        mut prices: Vec<(u64, u64)>,
+    ) -> Option<Self> {
+        prices.sort_unstable();
+        let listings = prices.len() as u64;
+        let quantity: u64 = prices.iter().map(|(_, q)| q).sum();
+        if quantity == 0 {
+            return None;
+        }
+        let total: u128 = prices.iter().map(|(p, q)| *p as u128 * *q as u128).sum();
+        let percentile = |pct: u64| {
+            let rank = ((quantity * pct) as f64 / 100.0).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            prices
+                .iter()
+                .find(|(_, q)| {
+                    seen += q;
+                    seen >= rank
+                })
+                .map(|(p, _)| *p)
+                .unwrap_or(prices[prices.len() - 1].0)
+        };
+        Some(MarketStats {
+            market,
+            item_id,
+            variant,
+            min: prices[0].0,
+            p10: percentile(10),
+            p25: percentile(25),
+            median: percentile(50),
+            p75: percentile(75),
+            mean: (total / quantity as u128) as u64,
+            quantity,
+            listings,
+        })
+    }
+
+    /// Each statistic paired with its name
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        Self::NAMES
             .iter()
-            .filter(|a| a.unit_price.is_some())
-            .sorted_by_key(|x| x.unit_price.unwrap())
-            .rev()
-            .group_by(|x| x.item.id)
-            .into_iter()
-            .map(|(iid, au)| (iid, au.take(1).next().unwrap()))
-            .map(|(iid, au)| crate::actors::AuctionRow {
-                item_id: iid,
-                auction_id: au.id,
-                quantity: au.quantity,
-                unit_price: au.unit_price.unwrap(),
-            })
+            .cloned()
+            .zip(vec![
+                self.min,
+                self.p10,
+                self.p25,
+                self.median,
+                self.p75,
+                self.mean,
+                self.quantity,
+                self.listings,
+            ])
             .collect()
     }
 }
 
+impl AsKey for MarketStats {
+    fn id(&self) -> String {
+        variant_key(self.item_id, self.variant.as_deref())
+    }
+
+    fn prefix(&self) -> Option<String> {
+        Some(format!("auc:{}", self.market.namespace()))
+    }
+}
+
 /// An individual auction
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Auction {
     pub id: u64,
     pub item: ItemIden,
@@ -76,7 +497,18 @@ pub struct Auction {
     pub time_left: AuctionTime,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl Auction {
+    /// The buyout price of a single unit, from `unit_price` for commodities or else the buyout
+    pub fn price_per_unit(&self) -> Option<u64> {
+        self.unit_price.or_else(|| {
+            self.buyout
+                .filter(|_| self.quantity > 0)
+                .map(|b| b / self.quantity as u64)
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum AuctionTime {
     SHORT,
     MEDIUM,
@@ -90,15 +522,108 @@ impl fmt::Display for AuctionTime {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl std::str::FromStr for AuctionTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "SHORT" => Ok(AuctionTime::SHORT),
+            "MEDIUM" => Ok(AuctionTime::MEDIUM),
+            "LONG" => Ok(AuctionTime::LONG),
+            "VERY_LONG" => Ok(AuctionTime::VERY_LONG),
+            _ => Err(Error::IOError(format!("Unknown auction time {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ItemIden {
     pub id: u64,
-    context: Option<u16>,
+    pub context: Option<u16>,
+    /// Bonuses applied to the item, e.g. raising its item level
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bonus_lists: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modifiers: Vec<ItemModifier>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pet_breed_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pet_level: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pet_quality_id: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pet_species_id: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ItemModifier {
+    #[serde(rename = "type")]
+    pub kind: u32,
+    pub value: u32,
+}
+
+/// The item id every caged battle pet is listed under
+pub const PET_CAGE: u64 = 82800;
+
+/// A caged battle pet, as told apart for pricing. The breed is left out, as it only shifts stats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Pet {
+    pub species_id: u32,
+    pub level: u8,
+    /// From 0 for poor to 5 for legendary
+    pub quality_id: u8,
+}
+
+impl Pet {
+    /// The pet's variant of `PET_CAGE`, `s{species}.l{level}.q{quality}`
+    pub fn variant(&self) -> String {
+        format!("s{}.l{}.q{}", self.species_id, self.level, self.quality_id)
+    }
+}
+
+impl ItemIden {
+    /// The caged battle pet, if this is one
+    pub fn pet(&self) -> Option<Pet> {
+        Some(Pet {
+            species_id: self.pet_species_id?,
+            level: self.pet_level.unwrap_or(1),
+            quality_id: self.pet_quality_id.unwrap_or_default(),
+        })
+    }
+
+    /// What tells this apart from other listings of the same item id: the species, level and
+    /// quality of a caged battle pet (see `Pet::variant`), or else the bonuses on a piece of gear
+    /// (`b{id}.{id}...`, sorted). Modifiers aren't included, as they mostly record things like the
+    /// level it dropped at.
+    pub fn variant(&self) -> Option<String> {
+        if let Some(pet) = self.pet() {
+            Some(pet.variant())
+        } else if self.bonus_lists.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "b{}",
+                self.bonus_lists
+                    .iter()
+                    .sorted()
+                    .map(|b| b.to_string())
+                    .join(".")
+            ))
+        }
+    }
+}
+
+/// The id part of a key for the item, which for a variant is `{item_id}-{variant}`
+pub fn variant_key(item_id: u64, variant: Option<&str>) -> String {
+    match variant {
+        Some(v) => format!("{}-{}", item_id, v),
+        None => item_id.to_string(),
+    }
 }
 
 impl AsKey for ItemIden {
     fn id(&self) -> String {
-        self.id.to_string()
+        variant_key(self.id, self.variant().as_deref())
     }
 
     fn prefix(&self) -> Option<String> {
@@ -106,10 +631,140 @@ impl AsKey for ItemIden {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Item {
     pub id: u64,
     pub en_us: String,
+    /// The name in each locale the API knows it in, keyed by locale, e.g. `de_DE`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<String, String>,
+    /// e.g. `EPIC`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subclass_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<u32>,
+    /// What a vendor pays for one, in copper
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sell_price: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stackable: Option<bool>,
+}
+
+/// The locales the Game Data API gives names in
+pub const LOCALES: [&str; 12] = [
+    "en_US", "es_MX", "pt_BR", "de_DE", "en_GB", "es_ES", "fr_FR", "it_IT", "ru_RU", "ko_KR",
+    "zh_TW", "zh_CN",
+];
+
+/// The locale of `Item::en_us`, used where no other is asked for
+pub const DEFAULT_LOCALE: &str = "en_US";
+
+impl Item {
+    /// The item's name in `locale`, or its English name if it has none in that locale
+    pub fn name(&self, locale: &str) -> &str {
+        self.names.get(locale).unwrap_or(&self.en_us)
+    }
+
+    /// Each name the item has with its locale, including `en_us`
+    pub fn localised_names(&self) -> Vec<(&str, &str)> {
+        let mut names: Vec<(&str, &str)> = self
+            .names
+            .iter()
+            .map(|(l, n)| (l.as_str(), n.as_str()))
+            .collect();
+        if !self.names.contains_key(DEFAULT_LOCALE) {
+            names.push((DEFAULT_LOCALE, &self.en_us));
+        }
+        names
+    }
+}
+
+/// An item as described by the Game Data API when asked for every locale
+#[derive(Debug, Deserialize)]
+pub struct ItemResponse {
+    pub id: u64,
+    pub name: BTreeMap<String, String>,
+    pub quality: Option<ItemQuality>,
+    pub level: Option<u32>,
+    pub item_class: Option<IdRef>,
+    pub item_subclass: Option<IdRef>,
+    pub sell_price: Option<u64>,
+    pub is_stackable: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ItemQuality {
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+/// A reference to another Game Data API document
+#[derive(Debug, Deserialize)]
+pub struct IdRef {
+    pub id: u32,
+}
+
+impl From<ItemResponse> for Item {
+    fn from(r: ItemResponse) -> Self {
+        Item {
+            id: r.id,
+            en_us: r
+                .name
+                .get("en_US")
+                .or_else(|| r.name.values().next())
+                .cloned()
+                .unwrap_or_default(),
+            names: r.name,
+            quality: r.quality.map(|q| q.kind),
+            class_id: r.item_class.map(|c| c.id),
+            subclass_id: r.item_subclass.map(|c| c.id),
+            level: r.level,
+            sell_price: r.sell_price,
+            stackable: r.is_stackable,
+        }
+    }
+}
+
+/// A battle pet species, named in each locale like `Item`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PetSpecies {
+    pub id: u32,
+    pub en_us: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<String, String>,
+}
+
+impl PetSpecies {
+    /// The species' name in `locale`, or its English name if it has none in that locale
+    pub fn name(&self, locale: &str) -> &str {
+        self.names.get(locale).unwrap_or(&self.en_us)
+    }
+}
+
+/// A battle pet species as described by the Game Data API when asked for every locale
+#[derive(Debug, Deserialize)]
+pub struct PetResponse {
+    pub id: u32,
+    pub name: BTreeMap<String, String>,
+}
+
+impl From<PetResponse> for PetSpecies {
+    fn from(r: PetResponse) -> Self {
+        PetSpecies {
+            id: r.id,
+            en_us: r
+                .name
+                .get(DEFAULT_LOCALE)
+                .or_else(|| r.name.values().next())
+                .cloned()
+                .unwrap_or_default(),
+            names: r.name,
+        }
+    }
 }
 
 impl redis::ToRedisArgs for Item {
@@ -139,3 +794,151 @@ impl redis::FromRedisValue for Item {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRAENOR: Target = Target {
+        region: Region::Eu,
+        realm_id: 1403,
+    };
+
+    fn auction(id: u64, item_id: u64, unit_price: u64, quantity: u16) -> Auction {
+        Auction {
+            id,
+            item: ItemIden {
+                id: item_id,
+                ..Default::default()
+            },
+            buyout: None,
+            unit_price: Some(unit_price),
+            quantity,
+            time_left: AuctionTime::LONG,
+        }
+    }
+
+    #[test]
+    fn market_stats() {
+        let mut ar = AuctionResponse {
+            connected_realm: Some(ConnectedRealmLink {
+                href: "https://eu.api.blizzard.com/data/wow/connected-realm/1403".to_string(),
+            }),
+            auctions: vec![
+                auction(1, 109119, 100, 10),
+                auction(2, 109119, 200, 70),
+                auction(3, 109119, 400, 20),
+                auction(4, 72092, 50, 1),
+                Auction {
+                    buyout: Some(900),
+                    unit_price: None,
+                    ..auction(5, 72092, 0, 3)
+                },
+            ],
+            market: Market::Realm(DRAENOR),
+            last_modified: None,
+            archived_as: None,
+        };
+        let stats = ar.market_stats();
+        assert_eq!(
+            stats,
+            vec![
+                MarketStats {
+                    market: Market::Realm(DRAENOR),
+                    item_id: 72092,
+                    variant: None,
+                    min: 50,
+                    p10: 50,
+                    p25: 50,
+                    median: 300,
+                    p75: 300,
+                    mean: 237,
+                    quantity: 4,
+                    listings: 2,
+                },
+                MarketStats {
+                    market: Market::Realm(DRAENOR),
+                    item_id: 109119,
+                    variant: None,
+                    min: 100,
+                    p10: 100,
+                    p25: 200,
+                    median: 200,
+                    p75: 200,
+                    mean: 230,
+                    quantity: 100,
+                    listings: 3,
+                },
+            ]
+        );
+        assert_eq!(stats[1].to_key(), "auc:eu:1403:item:109119");
+
+        ar.market = Market::Commodities(Region::Eu);
+        assert_eq!(ar.market_stats()[1].to_key(), "auc:eu:commodity:109119");
+
+        // Prices of the two items interleave
+        ar.auctions.push(auction(6, 72092, 150, 1));
+        let best: Vec<(u64, u64, u64)> = ar
+            .best_auctions()
+            .iter()
+            .map(|r| (r.item_id, r.auction_id, r.unit_price))
+            .collect();
+        assert_eq!(best, vec![(72092, 4, 50), (109119, 1, 100)]);
+    }
+
+    #[test]
+    fn variants() {
+        let mut ar: AuctionResponse = serde_json::from_value(serde_json::json!({
+            "auctions": [
+                { "id": 1, "item": { "id": 19019, "context": 5, "bonus_lists": [6646, 1487],
+                  "modifiers": [{ "type": 9, "value": 60 }] },
+                  "buyout": 5000, "quantity": 1, "time_left": "LONG" },
+                { "id": 2, "item": { "id": 19019, "bonus_lists": [1487, 6646] },
+                  "buyout": 7000, "quantity": 1, "time_left": "LONG" },
+                { "id": 3, "item": { "id": 19019 }, "buyout": 900, "quantity": 1, "time_left": "LONG" },
+                { "id": 4, "item": { "id": 82800, "pet_breed_id": 7, "pet_level": 1,
+                  "pet_quality_id": 3, "pet_species_id": 2716 },
+                  "buyout": 250000, "quantity": 1, "time_left": "SHORT" },
+                { "id": 5, "item": { "id": 82800, "pet_breed_id": 4, "pet_level": 25,
+                  "pet_quality_id": 3, "pet_species_id": 39 },
+                  "buyout": 90000, "quantity": 1, "time_left": "SHORT" },
+            ]
+        }))
+        .unwrap();
+        ar.market = Market::Realm(DRAENOR);
+        assert_eq!(ar.auctions[0].item.modifiers[0].kind, 9);
+        assert_eq!(ar.auctions[0].item.variant(), ar.auctions[1].item.variant());
+
+        let keys: Vec<(String, u64)> = ar
+            .market_stats()
+            .iter()
+            .map(|s| (s.to_key(), s.median))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("auc:eu:1403:item:19019".to_string(), 900),
+                ("auc:eu:1403:item:19019-b1487.6646".to_string(), 5000),
+                ("auc:eu:1403:item:82800-s2716.l1.q3".to_string(), 250000),
+                ("auc:eu:1403:item:82800-s39.l25.q3".to_string(), 90000),
+            ]
+        );
+        assert_eq!(ar.auctions[3].item.to_key(), "item:82800-s2716.l1.q3");
+        assert_eq!(
+            ar.auctions[4].item.pet(),
+            Some(Pet {
+                species_id: 39,
+                level: 25,
+                quality_id: 3
+            })
+        );
+        assert_eq!(ar.auctions[0].item.pet(), None);
+    }
+
+    #[test]
+    fn http_dates() {
+        let t = parse_http_date("Tue, 15 Sep 2020 09:31:02 GMT").unwrap();
+        assert_eq!(t.to_rfc3339(), "2020-09-15T09:31:02+00:00");
+        assert_eq!(http_date(t), "Tue, 15 Sep 2020 09:31:02 GMT");
+    }
+}
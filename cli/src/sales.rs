@@ -0,0 +1,159 @@
+use crate::listings::Listings;
+use crate::realm::{variant_key, AuctionTime, Market};
+use crate::AsKey;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+/// The sales of an item inferred from the difference between two consecutive snapshots
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SalesEstimate {
+    pub market: Market,
+    pub item_id: u64,
+    /// See `ItemIden::variant`
+    pub variant: Option<String>,
+    /// Units that were probably bought
+    pub units: u64,
+    /// Total price paid for those units
+    pub volume: u64,
+    /// Units on offer in the earlier snapshot
+    pub listed: u64,
+    /// `units` as a proportion of `listed`, in basis points (1/100th of a percent)
+    pub sell_through: u64,
+}
+
+impl SalesEstimate {
+    /// The names of each estimate, as used in series keys
+    pub const NAMES: [&'static str; 3] = ["units", "volume", "sell_through"];
+
+    /// Each estimate paired with its name
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        Self::NAMES
+            .iter()
+            .cloned()
+            .zip(vec![self.units, self.volume, self.sell_through])
+            .collect()
+    }
+}
+
+impl AsKey for SalesEstimate {
+    fn id(&self) -> String {
+        variant_key(self.item_id, self.variant.as_deref())
+    }
+
+    fn prefix(&self) -> Option<String> {
+        Some(format!("sales:{}", self.market.namespace()))
+    }
+}
+
+impl AuctionTime {
+    /// The least time an auction with this much time left can still have to run
+    pub fn min_remaining(&self) -> Duration {
+        match self {
+            AuctionTime::SHORT => Duration::zero(),
+            AuctionTime::MEDIUM => Duration::minutes(30),
+            AuctionTime::LONG => Duration::hours(2),
+            AuctionTime::VERY_LONG => Duration::hours(12),
+        }
+    }
+}
+
+/// Estimate what sold between the `prev` and `next` snapshots, taken `elapsed` apart.
+///
+/// An auction missing from `next` counts as sold only if its `time_left` in `prev` means it
+/// couldn't have expired in the meantime, so short auctions that vanish are never counted.
+/// Cancellations are indistinguishable from sales. A stack whose quantity dropped sold the
+/// difference.
+pub fn estimate_sales(prev: &Listings, next: &Listings, elapsed: Duration) -> Vec<SalesEstimate> {
+    prev.by_variant()
+        .into_iter()
+        .filter_map(|((item_id, variant), ls)| {
+            let (mut units, mut volume, mut listed, mut priced) = (0, 0, 0, false);
+            for a in ls {
+                let price = match a.price_per_unit {
+                    Some(price) => price,
+                    None => continue,
+                };
+                priced = true;
+                let sold = match next.get(a.id) {
+                    Some(n) => a.quantity.saturating_sub(n.quantity),
+                    None if a.time_left.min_remaining() > elapsed => a.quantity,
+                    None => 0,
+                } as u64;
+                units += sold;
+                volume += sold * price;
+                listed += a.quantity as u64;
+            }
+            if !priced {
+                return None;
+            }
+            Some(SalesEstimate {
+                market: prev.market,
+                item_id: *item_id,
+                variant: variant.clone(),
+                units,
+                volume,
+                listed,
+                sell_through: (units * 10_000).checked_div(listed).unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realm::{AuctionResponse, Region, Target};
+
+    const DRAENOR: Target = Target {
+        region: Region::Eu,
+        realm_id: 1403,
+    };
+
+    fn snapshot(auctions: serde_json::Value) -> AuctionResponse {
+        let mut ar: AuctionResponse = serde_json::from_value(serde_json::json!({
+            "connected_realm": { "href": "https://eu.api.blizzard.com/data/wow/connected-realm/1403" },
+            "auctions": auctions,
+        }))
+        .unwrap();
+        ar.market = Market::Realm(DRAENOR);
+        ar
+    }
+
+    #[test]
+    fn estimates_sales_between_snapshots() {
+        let prev = snapshot(serde_json::json!([
+            { "id": 1, "item": { "id": 109119 }, "unit_price": 100, "quantity": 20, "time_left": "VERY_LONG" },
+            { "id": 2, "item": { "id": 109119 }, "unit_price": 150, "quantity": 50, "time_left": "LONG" },
+            { "id": 3, "item": { "id": 109119 }, "unit_price": 200, "quantity": 30, "time_left": "SHORT" },
+            { "id": 4, "item": { "id": 72092 }, "buyout": 1000, "quantity": 5, "time_left": "MEDIUM" },
+        ]));
+        let next = snapshot(serde_json::json!([
+            { "id": 2, "item": { "id": 109119 }, "unit_price": 150, "quantity": 40, "time_left": "LONG" },
+            { "id": 4, "item": { "id": 72092 }, "buyout": 1000, "quantity": 5, "time_left": "MEDIUM" },
+        ]));
+
+        assert_eq!(
+            estimate_sales(&prev.listings(), &next.listings(), Duration::hours(1)),
+            vec![
+                SalesEstimate {
+                    market: Market::Realm(DRAENOR),
+                    item_id: 72092,
+                    variant: None,
+                    units: 0,
+                    volume: 0,
+                    listed: 5,
+                    sell_through: 0,
+                },
+                SalesEstimate {
+                    market: Market::Realm(DRAENOR),
+                    item_id: 109119,
+                    variant: None,
+                    units: 30,
+                    volume: 3500,
+                    listed: 100,
+                    sell_through: 3000,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,696 @@
+//! Streaming reads and writes of auction snapshots, so that a dump of hundreds of megabytes is
+//! never held in memory whole, only the auctions parsed from it.
+use crate::codec::{Codec, Encoder};
+use crate::listings::Listings;
+use crate::realm::{Auction, AuctionResponse, ConnectedRealmLink, Market, Region};
+use crate::Error;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+/// How many downloaded chunks may wait to be parsed before the download is held up
+const CHUNK_BACKLOG: usize = 64;
+
+/// The version of the archive layout, written to each manifest
+pub const ARCHIVE_SCHEMA: u32 = 2;
+
+/// What's known of an archived snapshot, kept beside it as `{stem}.manifest.json`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub schema: u32,
+    pub region: Region,
+    /// The connected realm, or none for commodities
+    pub realm: Option<u16>,
+    /// The API's `Last-Modified` for the snapshot, in RFC 3339
+    pub last_modified: Option<String>,
+    pub auctions: usize,
+    /// Of the compressed archive
+    pub sha256: String,
+    #[serde(default = "Manifest::legacy_codec")]
+    pub codec: Codec,
+    /// The SHA-256 of the zstd dictionary the archive was compressed with, if any
+    #[serde(default)]
+    pub dictionary: Option<String>,
+}
+
+impl Manifest {
+    fn new(to: ArchiveTo, market: Market, last_modified: Option<DateTime<Utc>>) -> Self {
+        let (region, realm) = match market {
+            Market::Realm(t) => (t.region, Some(t.realm_id)),
+            Market::Commodities(r) => (r, None),
+        };
+        Self {
+            schema: ARCHIVE_SCHEMA,
+            region,
+            realm,
+            last_modified: last_modified.map(|lm| lm.to_rfc3339()),
+            auctions: 0,
+            sha256: String::new(),
+            codec: to.codec,
+            dictionary: to
+                .dictionary
+                .filter(|_| to.codec == Codec::Zstd)
+                .map(sha256_of),
+        }
+    }
+
+    /// Every archive was xz before the codec was recorded
+    fn legacy_codec() -> Codec {
+        Codec::Xz
+    }
+
+    /// The manifest of an archive, if it has one. Snapshots archived before manifests were
+    /// written don't.
+    pub fn read(archive: &Path) -> Result<Option<Self>, Error> {
+        match File::open(manifest_path(archive)) {
+            Ok(f) => serde_json::from_reader(BufReader::new(f))
+                .map(Some)
+                .map_err(|e| Error::CorruptArchive(format!("Unreadable manifest - {}", e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn manifest_path(archive: &Path) -> PathBuf {
+    archive.with_extension("manifest.json")
+}
+
+/// Deserialise each auction of a snapshot's JSON in turn, handing it to `f`.
+///
+/// Returns the rest of the snapshot, with no auctions.
+pub fn for_each_auction<R: Read>(
+    reader: R,
+    f: impl FnMut(Auction),
+) -> Result<AuctionResponse, Error> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let ar = de.deserialize_map(SnapshotVisitor(f))?;
+    de.end()?;
+    Ok(ar)
+}
+
+/// Read a whole snapshot, e.g. from an archive
+pub fn read_snapshot<R: Read>(reader: R) -> Result<AuctionResponse, Error> {
+    let mut auctions = vec![];
+    let mut ar = for_each_auction(BufReader::new(reader), |a| auctions.push(a))?;
+    ar.auctions = auctions;
+    Ok(ar)
+}
+
+/// Read an archived snapshot, compressed with any codec, and the `dictionary` if it needs one
+pub fn read_archive(path: &Path, dictionary: Option<&[u8]>) -> Result<AuctionResponse, Error> {
+    read_snapshot(Codec::detect(path)?.reader(File::open(path)?, dictionary)?)
+}
+
+/// The snapshots archived in `dir`, oldest first, by the time each is named for
+pub fn archives(dir: &str) -> Result<Vec<(DateTime<Utc>, PathBuf)>, Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut archives = vec![];
+    for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+        if Codec::for_path(&path).is_none() {
+            continue;
+        }
+        match path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        {
+            Some(ts) => archives.push((ts.with_timezone(&Utc), path)),
+            None => warn!("Ignoring {}, which isn't named for a time", path.display()),
+        }
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+/// The SHA-256 of the file's contents, in hex
+pub fn sha256(path: &Path) -> Result<String, Error> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+fn sha256_of(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    format!("{:x}", sha2::Sha256::digest(bytes))
+}
+
+/// Read an archive whose contents hash to `sha256`, checking it against its manifest.
+///
+/// Fails with a `ConfigError`, rather than finding the archive corrupt, if it was compressed with
+/// a dictionary other than the one given.
+pub fn check_archive(
+    path: &Path,
+    sha256: &str,
+    dictionary: Option<&[u8]>,
+) -> Result<AuctionResponse, Error> {
+    let manifest = Manifest::read(path)?;
+    if let Some(m) = &manifest {
+        if m.sha256 != sha256 {
+            return Err(Error::CorruptArchive(format!(
+                "SHA-256 is {}, the manifest has {}",
+                sha256, m.sha256
+            )));
+        }
+        if let Some(needed) = &m.dictionary {
+            if dictionary.map(sha256_of).as_ref() != Some(needed) {
+                return Err(Error::ConfigError(format!(
+                    "{} needs the zstd dictionary with SHA-256 {}",
+                    path.display(),
+                    needed
+                )));
+            }
+        }
+    }
+    let ar =
+        read_archive(path, dictionary).map_err(|e| Error::CorruptArchive(format!("{:?}", e)))?;
+    match manifest {
+        Some(m) if m.auctions != ar.auctions.len() => Err(Error::CorruptArchive(format!(
+            "{} auctions, the manifest has {}",
+            ar.auctions.len(),
+            m.auctions
+        ))),
+        _ => Ok(ar),
+    }
+}
+
+/// Move an archive, with its manifest and JSON, into the `quarantine` directory beside it so it's
+/// no longer loaded. Returns where the archive went.
+pub fn quarantine(path: &Path) -> Result<PathBuf, Error> {
+    let dir = path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("quarantine");
+    std::fs::create_dir_all(&dir)?;
+    for file in &[
+        manifest_path(path),
+        path.with_extension("json"),
+        path.to_path_buf(),
+    ] {
+        if let Some(name) = file.file_name().filter(|_| file.exists()) {
+            std::fs::rename(file, dir.join(name))?;
+        }
+    }
+    let moved = dir.join(path.file_name().unwrap_or_default());
+    warn!("Quarantined {} as {}", path.display(), moved.display());
+    Ok(moved)
+}
+
+/// How long archived snapshots are kept, thinning out as they age
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Retention {
+    /// Keep every snapshot up to this many days old
+    pub all_days: i64,
+    /// Then the first of each hour for this many days more
+    #[serde(default)]
+    pub hourly_days: i64,
+    /// Then the first of each day for this many days more, or for good if unset
+    pub daily_days: Option<i64>,
+    /// Whether `sync` prunes a market's archives each time it saves a snapshot to them
+    #[serde(default)]
+    pub prune_on_sync: bool,
+}
+
+impl Retention {
+    /// The archives, oldest first as from `archives`, that have aged out by `now`
+    pub fn expired<'a>(
+        &self,
+        archives: &'a [(DateTime<Utc>, PathBuf)],
+        now: DateTime<Utc>,
+    ) -> Vec<&'a Path> {
+        let hourly = now - Duration::days(self.all_days);
+        let daily = hourly - Duration::days(self.hourly_days);
+        let gone = self.daily_days.map(|d| daily - Duration::days(d));
+        let mut kept = HashSet::new();
+        archives
+            .iter()
+            .filter(|(ts, _)| {
+                let bucket = if *ts >= hourly {
+                    return false;
+                } else if *ts >= daily {
+                    ts.format("%Y-%m-%dT%H").to_string()
+                } else if matches!(gone, Some(gone) if *ts < gone) {
+                    return true;
+                } else {
+                    ts.format("%Y-%m-%d").to_string()
+                };
+                !kept.insert(bucket)
+            })
+            .map(|(_, path)| path.as_path())
+            .collect()
+    }
+}
+
+/// Delete the archives in `dir` that have aged out of the retention policy, if there is one,
+/// and without `json` the raw `.json` beside any archive kept. Returns the files deleted, or
+/// that would be on a `dry_run`.
+pub fn prune(
+    dir: &str,
+    retention: Option<&Retention>,
+    json: bool,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    let archives = archives(dir)?;
+    let expired: HashSet<&Path> = match retention {
+        Some(retention) => retention
+            .expired(&archives, Utc::now())
+            .into_iter()
+            .collect(),
+        None => HashSet::new(),
+    };
+    let mut pruned = vec![];
+    for (_, path) in archives.iter() {
+        let files = if expired.contains(path.as_path()) {
+            vec![
+                path.with_extension("json"),
+                manifest_path(path),
+                path.to_path_buf(),
+            ]
+        } else if !json {
+            vec![path.with_extension("json")]
+        } else {
+            continue;
+        };
+        for file in files.into_iter().filter(|f| f.exists()) {
+            if !dry_run {
+                std::fs::remove_file(&file)?;
+            }
+            pruned.push(file);
+        }
+    }
+    info!("Pruned {} files from {}", pruned.len(), dir);
+    Ok(pruned)
+}
+
+struct SnapshotVisitor<F>(F);
+
+impl<'de, F: FnMut(Auction)> Visitor<'de> for SnapshotVisitor<F> {
+    type Value = AuctionResponse;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an auctions response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut connected_realm: Option<ConnectedRealmLink> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "auctions" => map.next_value_seed(AuctionsSeed(&mut self.0))?,
+                "connected_realm" => connected_realm = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(AuctionResponse::new(connected_realm))
+    }
+}
+
+/// The `auctions` array, passed on an auction at a time rather than collected
+struct AuctionsSeed<'a, F>(&'a mut F);
+
+impl<'de, 'a, F: FnMut(Auction)> DeserializeSeed<'de> for AuctionsSeed<'a, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, F: FnMut(Auction)> Visitor<'de> for AuctionsSeed<'a, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of auctions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(auction) = seq.next_element()? {
+            (self.0)(auction);
+        }
+        Ok(())
+    }
+}
+
+/// Where, and how, to archive snapshots as they download
+#[derive(Clone, Copy, Debug)]
+pub struct ArchiveTo<'a> {
+    pub dir: &'a str,
+    /// Whether to keep the raw `.json` beside the compressed archive
+    pub json: bool,
+    pub codec: Codec,
+    /// For zstd, a dictionary trained on auction JSON
+    pub dictionary: Option<&'a [u8]>,
+}
+
+/// Writes a snapshot's raw JSON to `{stem}.json`, unless told not to, and compressed to
+/// `{stem}.{codec extension}` in a directory as it arrives, then its `Manifest`.
+///
+/// Both are written under a `.part` suffix and only renamed into place by `finish`, so an
+/// interrupted download never leaves a truncated snapshot to be loaded.
+pub struct Archiver {
+    path: String,
+    json: Option<BufWriter<File>>,
+    archive: Encoder,
+    manifest: Manifest,
+}
+
+impl Archiver {
+    pub fn create(
+        to: ArchiveTo,
+        stem: &str,
+        market: Market,
+        last_modified: Option<DateTime<Utc>>,
+    ) -> Result<Self, Error> {
+        std::fs::create_dir_all(to.dir)?;
+        let path = format!("{}/{}", to.dir, stem);
+        let json = if to.json {
+            Some(BufWriter::new(File::create(format!("{}.json.part", path))?))
+        } else {
+            None
+        };
+        Ok(Self {
+            json,
+            archive: to.codec.writer(
+                File::create(format!("{}.{}.part", path, to.codec.extension()))?,
+                to.dictionary,
+            )?,
+            manifest: Manifest::new(to, market, last_modified),
+            path,
+        })
+    }
+
+    /// The extensions of the files being written
+    fn extensions(&self) -> Vec<&'static str> {
+        let archive = self.manifest.codec.extension();
+        match self.json {
+            Some(_) => vec!["json", archive],
+            None => vec![archive],
+        }
+    }
+
+    /// Flush everything written, of a snapshot of so many auctions, and move the files into
+    /// place. The manifest goes first, so every archive in place has one.
+    pub fn finish(mut self, auctions: usize) -> Result<(), Error> {
+        let extensions = self.extensions();
+        if let Some(json) = self.json.as_mut() {
+            json.flush()?;
+        }
+        self.archive.finish()?;
+        let archive = format!("{}.{}.part", self.path, self.manifest.codec.extension());
+        self.manifest.auctions = auctions;
+        self.manifest.sha256 = sha256(archive.as_ref())?;
+        std::fs::write(
+            format!("{}.manifest.json", self.path),
+            serde_json::to_vec_pretty(&self.manifest)?,
+        )?;
+        for ext in extensions {
+            std::fs::rename(
+                format!("{}.{}.part", self.path, ext),
+                format!("{}.{}", self.path, ext),
+            )?;
+        }
+        info!("Auctions saved {}", self.path);
+        Ok(())
+    }
+
+    /// Give up on the snapshot, removing what was written of it
+    pub fn discard(self) {
+        let path = self.path.clone();
+        let extensions = self.extensions();
+        drop(self);
+        for ext in extensions {
+            if let Err(e) = std::fs::remove_file(format!("{}.{}.part", path, ext)) {
+                warn!("Couldn't remove partial snapshot {}.{}: {}", path, ext, e);
+            }
+        }
+    }
+}
+
+impl Write for Archiver {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(json) = self.json.as_mut() {
+            json.write_all(buf)?;
+        }
+        self.archive.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.json.as_mut() {
+            Some(json) => json.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the chunks sent down a channel, ending when the sender is dropped
+struct ChunkReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Copies everything read from `inner` to the archive, if there is one
+struct Tee<R> {
+    inner: R,
+    archive: Option<Archiver>,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(archive) = self.archive.as_mut() {
+            archive.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Parses a snapshot into its `Listings`, and archives it if given an `Archiver`, on a thread of
+/// its own as its chunks are downloaded.
+///
+/// Once `CHUNK_BACKLOG` chunks are waiting, `send` waits for the parser off the executor, so a
+/// slow parse or compression holds the download back rather than letting chunks pile up in
+/// memory.
+pub struct SnapshotParser {
+    chunks: SyncSender<Vec<u8>>,
+    parsed: tokio::sync::oneshot::Receiver<Result<Listings, Error>>,
+}
+
+impl SnapshotParser {
+    pub fn spawn(market: Market, archive: Option<Archiver>) -> Self {
+        let (chunks, rx) = sync_channel(CHUNK_BACKLOG);
+        let (tx, parsed) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(Tee {
+                inner: ChunkReader {
+                    chunks: rx,
+                    chunk: vec![],
+                    pos: 0,
+                },
+                archive,
+            });
+            let mut listings = Listings::new(market);
+            let res = for_each_auction(&mut reader, |a| listings.add(&a));
+            let archive = reader.into_inner().archive;
+            let res = match (res, archive) {
+                (Ok(_), Some(archive)) => archive.finish(listings.len()),
+                (Err(e), Some(archive)) => {
+                    archive.discard();
+                    Err(e)
+                }
+                (res, None) => res.map(|_| ()),
+            };
+            let _ = tx.send(res.map(|_| listings));
+        });
+        Self { chunks, parsed }
+    }
+
+    /// Pass on the next chunk of the body. Yields `false` if the parse has stopped, as it does
+    /// on failing, in which case `finish` tells why.
+    pub async fn send(&self, chunk: Vec<u8>) -> Result<bool, Error> {
+        let chunk = match self.chunks.try_send(chunk) {
+            Ok(()) => return Ok(true),
+            Err(TrySendError::Disconnected(_)) => return Ok(false),
+            Err(TrySendError::Full(chunk)) => chunk,
+        };
+        let chunks = self.chunks.clone();
+        tokio::task::spawn_blocking(move || chunks.send(chunk).is_ok())
+            .await
+            .map_err(|e| Error::IOError(format!("Waiting on the snapshot parser failed: {}", e)))
+    }
+
+    /// The listings parsed from every chunk sent
+    pub async fn finish(self) -> Result<Listings, Error> {
+        drop(self.chunks);
+        self.parsed
+            .await
+            .map_err(|_| Error::IOError("Snapshot parser panicked".to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNAPSHOT: &str = r#"{
+        "_links": { "self": { "href": "https://eu.api.blizzard.com/data/wow/connected-realm/1403/auctions" } },
+        "connected_realm": { "href": "https://eu.api.blizzard.com/data/wow/connected-realm/1403" },
+        "auctions": [
+            { "id": 1, "item": { "id": 109119 }, "unit_price": 100, "quantity": 20, "time_left": "LONG" },
+            { "id": 2, "item": { "id": 82800, "pet_species_id": 39, "pet_level": 25 }, "buyout": 90000, "quantity": 1, "time_left": "SHORT" }
+        ]
+    }"#;
+
+    const DRAENOR: Market = Market::Realm(crate::realm::Target {
+        region: Region::Eu,
+        realm_id: 1403,
+    });
+
+    #[tokio::test]
+    async fn parses_and_archives_in_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let dictionary = Some(&SNAPSHOT.as_bytes()[300..]);
+        let to = ArchiveTo {
+            dir,
+            json: true,
+            codec: Codec::Zstd,
+            dictionary,
+        };
+        let parser = SnapshotParser::spawn(
+            DRAENOR,
+            Some(Archiver::create(to, "snap", DRAENOR, None).unwrap()),
+        );
+        for chunk in SNAPSHOT.as_bytes().chunks(7) {
+            assert!(parser.send(chunk.to_vec()).await.unwrap());
+        }
+        let listings = parser.finish().await.unwrap();
+        assert_eq!(listings.len(), 2);
+        assert!(listings.pet_species().contains(&39));
+
+        let json = std::fs::read_to_string(format!("{}/snap.json", dir)).unwrap();
+        assert_eq!(json, SNAPSHOT);
+        let zst = Path::new(dir).join("snap.zst");
+        let ar = read_archive(&zst, dictionary).unwrap();
+        assert_eq!(ar.auctions.len(), 2);
+        assert_eq!(ar.auctions[1].item.pet_species_id, Some(39));
+        let manifest = Manifest::read(&zst).unwrap().unwrap();
+        assert_eq!((manifest.realm, manifest.auctions), (Some(1403), 2));
+        assert_eq!(
+            check_archive(&zst, &manifest.sha256, dictionary).unwrap(),
+            ar
+        );
+        assert!(matches!(
+            check_archive(&zst, "0000", dictionary),
+            Err(Error::CorruptArchive(_))
+        ));
+        assert!(matches!(
+            check_archive(&zst, &manifest.sha256, None),
+            Err(Error::ConfigError(_))
+        ));
+
+        let to = ArchiveTo {
+            dir,
+            json: false,
+            codec: Codec::Xz,
+            dictionary: None,
+        };
+        let parser = SnapshotParser::spawn(
+            DRAENOR,
+            Some(Archiver::create(to, "cut", DRAENOR, None).unwrap()),
+        );
+        assert!(parser
+            .send(SNAPSHOT.as_bytes()[..100].to_vec())
+            .await
+            .unwrap());
+        assert!(parser.finish().await.is_err());
+
+        // Once the parse fails, chunks are turned away and the failure is what's reported
+        let parser = SnapshotParser::spawn(DRAENOR, None);
+        let mut sent = 0;
+        while parser.send(b"{ not json".to_vec()).await.unwrap() {
+            sent += 1;
+            assert!(sent <= 10 * CHUNK_BACKLOG, "Parser never stopped");
+        }
+        match parser.finish().await {
+            Err(Error::IOError(e)) => assert!(e.contains("JSON"), "{}", e),
+            res => panic!("Expected the parse to fail, got {:?}", res.map(|l| l.len())),
+        }
+        assert!(std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(Result::ok)
+            .all(|e| e.file_name().to_str().unwrap().starts_with("snap.")));
+
+        let moved = quarantine(&zst).unwrap();
+        assert!(Manifest::read(&moved).unwrap().is_some());
+        assert!(archives(dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn thins_out_with_age() {
+        let now = DateTime::parse_from_rfc3339("2020-09-15T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let every_20_mins: Vec<_> = (1..=5 * 24 * 3)
+            .rev()
+            .map(|i| {
+                (
+                    now - Duration::minutes(20 * i),
+                    PathBuf::from(i.to_string()),
+                )
+            })
+            .collect();
+        let retention = Retention {
+            all_days: 1,
+            hourly_days: 1,
+            daily_days: Some(2),
+            prune_on_sync: false,
+        };
+        let expired = retention.expired(&every_20_mins, now);
+        let kept: Vec<_> = every_20_mins
+            .iter()
+            .filter(|(_, p)| !expired.contains(&p.as_path()))
+            .map(|(ts, _)| *ts)
+            .collect();
+        // The first of each of the three calendar days touched, of 24 hours, then all of the last day
+        assert_eq!(kept.len(), 3 + 24 + 72);
+        assert_eq!(kept[0], now - Duration::days(4));
+        assert_eq!(kept[1], now - Duration::days(3) - Duration::hours(12));
+        assert_eq!(kept[2], now - Duration::days(2) - Duration::hours(12));
+        assert_eq!(kept[3], now - Duration::days(2));
+        assert_eq!(kept[4], now - Duration::days(2) + Duration::hours(1));
+        assert_eq!(kept[27], now - Duration::days(1));
+    }
+}
@@ -0,0 +1,334 @@
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::RowAccessor;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+use waw::codec::Codec;
+use waw::db::sqlite::SqliteStore;
+use waw::db::PriceStore;
+use waw::realm::{Market, Region, Target};
+use waw::snapshot::{ArchiveTo, Archiver};
+
+const TIMES: [&str; 3] = [
+    "2020-09-15T09:00:00+00:00",
+    "2020-09-15T10:00:00+00:00",
+    "2020-09-15T11:00:00+00:00",
+];
+
+/// Archive a snapshot of True Iron Ore listings, one of which sells each hour, with a different
+/// codec each hour
+fn archive(data_dir: &Path, hour: usize) {
+    let auctions: Vec<serde_json::Value> = (hour..3)
+        .map(|id| {
+            serde_json::json!({
+                "id": id, "item": { "id": 109119 }, "unit_price": 100 * (id + 1),
+                "quantity": 10, "time_left": "VERY_LONG"
+            })
+        })
+        .collect();
+    let dir = data_dir.join("eu").join("1403");
+    let draenor = Market::Realm(Target {
+        region: Region::Eu,
+        realm_id: 1403,
+    });
+    let mut archiver = Archiver::create(
+        ArchiveTo {
+            dir: dir.to_str().unwrap(),
+            json: true,
+            codec: [Codec::Xz, Codec::Zstd, Codec::Gzip][hour],
+            dictionary: None,
+        },
+        TIMES[hour],
+        draenor,
+        None,
+    )
+    .unwrap();
+    archiver
+        .write_all(&serde_json::to_vec(&serde_json::json!({ "auctions": auctions })).unwrap())
+        .unwrap();
+    archiver.finish(auctions.len()).unwrap();
+}
+
+fn waw(dir: &Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_waw"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn load(dir: &Path, args: &[&str]) -> String {
+    let out = waw(dir, &[&["load"], args].concat());
+    assert!(out.status.success(), "{:?}", out);
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// A sqlite-backed setup in a fresh directory, with an archived snapshot for each of `TIMES`.
+///
+/// The directory is removed when the `TempDir` is dropped, so keep it for the whole test.
+fn setup() -> (TempDir, PathBuf, PathBuf) {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let data_dir = dir.join("data");
+    std::fs::write(
+        dir.join("Settings.toml"),
+        format!(
+            r#"
+client_id = "client"
+client_secret = "secret"
+realm_id = 1403
+data_dir = "{}"
+delay_mins = 1
+save_flexbuffer = false
+db_host = "localhost"
+backend = "sqlite"
+"#,
+            data_dir.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    for hour in 0..3 {
+        archive(&data_dir, hour);
+    }
+    (tmp, dir, data_dir)
+}
+
+#[test]
+fn replays_archives_into_the_store() {
+    let (_tmp, dir, data_dir) = setup();
+
+    // Forced the second time, to check a re-run leaves the same points behind
+    load(&dir, &["--since", TIMES[1]]);
+    load(&dir, &["--since", TIMES[1], "--force"]);
+    let mut store = SqliteStore::open(data_dir.join("waw.sqlite").to_str().unwrap()).unwrap();
+    let ts = |t: &str| chrono::DateTime::parse_from_rfc3339(t).unwrap().timestamp();
+    assert_eq!(
+        store.get_range("auc:eu:1403:item:109119:min").unwrap(),
+        vec![(ts(TIMES[1]), 200), (ts(TIMES[2]), 300)]
+    );
+    assert_eq!(store.get_range("auc:eu:1403:item:109119").unwrap().len(), 2);
+    assert_eq!(
+        store.get_range("auc:eu:1403:item:109119:listings").unwrap(),
+        vec![(ts(TIMES[1]), 2), (ts(TIMES[2]), 1)]
+    );
+    // The snapshot before the window is the baseline for the first one's sales
+    assert_eq!(
+        store.get_range("sales:eu:1403:item:109119:units").unwrap(),
+        vec![(ts(TIMES[1]), 10), (ts(TIMES[2]), 10)]
+    );
+
+    // The ledger has every snapshot in the window as loaded, so a fresh store is left empty
+    drop(store);
+    std::fs::remove_file(data_dir.join("waw.sqlite")).unwrap();
+    load(&dir, &[]);
+    let mut store = SqliteStore::open(data_dir.join("waw.sqlite").to_str().unwrap()).unwrap();
+    assert_eq!(
+        store.get_range("auc:eu:1403:item:109119:min").unwrap(),
+        vec![(ts(TIMES[0]), 100)]
+    );
+    // Unless it's forced
+    load(&dir, &["--force"]);
+    assert_eq!(
+        store
+            .get_range("auc:eu:1403:item:109119:min")
+            .unwrap()
+            .len(),
+        3
+    );
+
+    let resp = load(&dir, &["--format", "resp", "--until", TIMES[1]]);
+    assert!(resp.starts_with("*15\r\n$6\r\nTS.ADD\r\n$23\r\nauc:eu:1403:item:109119\r\n"));
+    assert!(resp.contains(&format!("\r\n{}\r\n", ts(TIMES[0]))));
+    assert_eq!(resp.matches("TS.ADD").count(), 1);
+}
+
+#[test]
+fn quarantines_corrupt_archives() {
+    let (_tmp, dir, data_dir) = setup();
+    let realm_dir = data_dir.join("eu").join("1403");
+    assert!(waw(&dir, &["archive", "verify"]).status.success());
+    let corrupt = realm_dir.join(format!("{}.zst", TIMES[1]));
+    std::fs::write(&corrupt, b"not zstd").unwrap();
+    assert!(!waw(&dir, &["archive", "verify"]).status.success());
+
+    // The rest still load
+    load(&dir, &[]);
+    let mut store = SqliteStore::open(data_dir.join("waw.sqlite").to_str().unwrap()).unwrap();
+    let ts = |t: &str| chrono::DateTime::parse_from_rfc3339(t).unwrap().timestamp();
+    assert_eq!(
+        store.get_range("auc:eu:1403:item:109119:min").unwrap(),
+        vec![(ts(TIMES[0]), 100), (ts(TIMES[2]), 300)]
+    );
+    assert!(!corrupt.exists());
+    let quarantined = realm_dir.join("quarantine");
+    assert!(quarantined.join(format!("{}.zst", TIMES[1])).exists());
+    assert!(quarantined
+        .join(format!("{}.manifest.json", TIMES[1]))
+        .exists());
+    assert!(waw(&dir, &["archive", "verify"]).status.success());
+}
+
+#[test]
+fn verifies_every_archive_despite_unreadable_manifests() {
+    let (_tmp, dir, data_dir) = setup();
+    let realm_dir = data_dir.join("eu").join("1403");
+    std::fs::write(
+        realm_dir.join(format!("{}.manifest.json", TIMES[0])),
+        b"not json",
+    )
+    .unwrap();
+    std::fs::write(realm_dir.join(format!("{}.gz", TIMES[2])), b"not gzip").unwrap();
+
+    let out = waw(&dir, &["archive", "verify"]);
+    assert!(!out.status.success(), "{:?}", out);
+    let failed = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(failed.lines().count(), 2, "{}", failed);
+    assert!(failed.contains(&format!("{}.xz", TIMES[0])));
+    assert!(failed.contains(&format!("{}.gz", TIMES[2])));
+}
+
+#[test]
+fn prunes_aged_archives() {
+    let (_tmp, dir, data_dir) = setup();
+    let mut settings = std::fs::read_to_string(dir.join("Settings.toml")).unwrap();
+    settings.push_str("archive_json = false\n[retention]\nall_days = 1\ndaily_days = 100000\n");
+    std::fs::write(dir.join("Settings.toml"), settings).unwrap();
+    let realm_dir = data_dir.join("eu").join("1403");
+    let files = || {
+        let mut files: Vec<String> = std::fs::read_dir(&realm_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files();
+
+    let out = waw(&dir, &["archive", "prune", "--dry-run"]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(String::from_utf8(out.stdout).unwrap().lines().count(), 7);
+    assert_eq!(files(), before);
+
+    // Only the first of the day is kept, and only its .xz and manifest
+    assert!(waw(&dir, &["archive", "prune"]).status.success());
+    assert_eq!(
+        files(),
+        vec![
+            format!("{}.manifest.json", TIMES[0]),
+            format!("{}.xz", TIMES[0])
+        ]
+    );
+}
+
+#[test]
+fn exports_parquet_by_day() {
+    let (_tmp, dir, data_dir) = setup();
+    let out = waw(&dir, &["export", "parquet", "--since", TIMES[1]]);
+    assert!(out.status.success(), "{:?}", out);
+
+    // The whole day is exported, not just from TIMES[1], as its file is rewritten
+    let day = data_dir.join("parquet/region=eu/realm=1403/day=2020-09-15/auctions.parquet");
+    let reader = SerializedFileReader::new(std::fs::File::open(&day).unwrap()).unwrap();
+    // A row group per snapshot
+    assert_eq!(reader.metadata().num_row_groups(), 3);
+    let rows: Vec<_> = reader
+        .get_row_iter(None)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(rows.len(), 6);
+    let ts = chrono::DateTime::parse_from_rfc3339(TIMES[0]).unwrap();
+    assert_eq!(
+        rows[0].get_timestamp_millis(0).unwrap(),
+        ts.timestamp_millis()
+    );
+    assert_eq!(rows[0].get_long(2).unwrap(), 109119);
+    assert_eq!(rows[0].get_int(4).unwrap(), 10);
+    assert_eq!(rows[0].get_long(5).unwrap(), 100);
+    assert_eq!(rows[0].get_string(7).unwrap(), "VERY_LONG");
+}
+
+#[test]
+fn exports_overlapping_parquet_windows() {
+    let (_tmp, dir, data_dir) = setup();
+    let day = data_dir.join("parquet/region=eu/realm=1403/day=2020-09-15/auctions.parquet");
+    let row_groups = || {
+        let reader = SerializedFileReader::new(std::fs::File::open(&day).unwrap()).unwrap();
+        reader.metadata().num_row_groups()
+    };
+
+    for args in &[
+        ["--until", TIMES[1]],
+        ["--since", TIMES[1]],
+        ["--until", "2020-09-15T10:30:00Z"],
+    ] {
+        let out = waw(&dir, &[&["export", "parquet"], &args[..]].concat());
+        assert!(out.status.success(), "{:?}", out);
+        assert_eq!(row_groups(), 3, "{:?} left part of the day", args);
+    }
+
+    // Windows missing the day leave its file be
+    let out = waw(
+        &dir,
+        &["export", "parquet", "--since", "2020-09-16T00:00:00Z"],
+    );
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(row_groups(), 3);
+}
+
+#[test]
+fn exports_series() {
+    let (_tmp, dir, data_dir) = setup();
+    load(&dir, &[]);
+    let mut store = SqliteStore::open(data_dir.join("waw.sqlite").to_str().unwrap()).unwrap();
+    store
+        .store_items(vec![waw::realm::Item {
+            id: 109119,
+            en_us: "True Iron Ore".to_string(),
+            ..Default::default()
+        }])
+        .unwrap();
+    let ts = |t: &str| chrono::DateTime::parse_from_rfc3339(t).unwrap().timestamp();
+    let export = |args: &[&str]| {
+        let out = waw(&dir, &[&["export", "series"], args].concat());
+        assert!(out.status.success(), "{:?}", out);
+        String::from_utf8(out.stdout).unwrap()
+    };
+
+    let csv = export(&["109119", "--stat", "min", "--since", TIMES[1]]);
+    assert_eq!(
+        csv,
+        format!(
+            "item,name,variant,ts,value\n109119,True Iron Ore,,{},200\n109119,True Iron Ore,,{},300\n",
+            ts(TIMES[1]),
+            ts(TIMES[2])
+        )
+    );
+
+    let out = data_dir.join("series.ndjson");
+    export(&[
+        "True Iron Ore",
+        "--stat",
+        "min",
+        "--bucket",
+        "1d",
+        "--aggregation",
+        "max",
+        "--format",
+        "ndjson",
+        "--out",
+        out.to_str().unwrap(),
+    ]);
+    let day = ts(TIMES[0]) - ts(TIMES[0]) % 86400;
+    assert_eq!(
+        std::fs::read_to_string(out).unwrap(),
+        format!(
+            "{{\"item\":109119,\"name\":\"True Iron Ore\",\"variant\":null,\"ts\":{},\"value\":300}}\n",
+            day
+        )
+    );
+    assert!(!waw(&dir, &["export", "series", "No Such Ore"])
+        .status
+        .success());
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use waw::db::Backend;
+use waw::realm::{Market, Region, Target};
+use waw::{Error, SessionManager, Settings};
+use waw_mock::{spawn, Knobs};
+
+const DRAENOR: Target = Target {
+    region: Region::Eu,
+    realm_id: 1403,
+};
+
+fn settings(url: &str, data_dir: &Path) -> Settings {
+    Settings {
+        client_id: "client".to_string(),
+        client_secret: "secret".to_string(),
+        realm_id: Some(DRAENOR.realm_id),
+        targets: vec![],
+        data_dir: data_dir.to_str().unwrap().to_string(),
+        delay_mins: 60,
+        save_flexbuffer: false,
+        db_host: "localhost".to_string(),
+        backend: Backend::Memory,
+        sqlite_path: None,
+        persist_token: false,
+        requests_per_sec: Some(50.0),
+        max_retries: Some(2),
+        max_retry_after_secs: None,
+        api_url: Some(url.to_string()),
+        oauth_url: Some(url.to_string()),
+        item_fetch_limit: None,
+        pet_fetch_limit: None,
+        archive_json: None,
+        retention: None,
+        archive_codec: Default::default(),
+        zstd_dictionary: None,
+    }
+}
+
+#[tokio::test]
+async fn fetches_snapshots_once_each() {
+    let mock = spawn(Knobs::default());
+    let dir = tempfile::tempdir().unwrap();
+    let sessions = SessionManager::new(settings(&mock.url, dir.path()));
+
+    let ar = sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(ar.market, Market::Realm(DRAENOR));
+    assert_eq!(ar.len(), 5);
+    assert!(ar.last_modified.is_some());
+    let commodities = Market::Commodities(Region::Eu);
+    assert_eq!(
+        sessions
+            .auctions(commodities)
+            .await
+            .unwrap()
+            .unwrap()
+            .market,
+        commodities
+    );
+
+    assert!(sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .is_none());
+    mock.knobs.touch();
+    assert!(sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .is_some());
+    // One token, then four snapshot requests
+    assert_eq!(mock.knobs.requests.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn fetches_snapshots_without_last_modified() {
+    let mock = spawn(Knobs::default());
+    let dir = tempfile::tempdir().unwrap();
+    let sessions = SessionManager::new(settings(&mock.url, dir.path()));
+
+    assert!(sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .is_some());
+    // Without the header there's nothing to tell the snapshot is stale by, so it's kept
+    mock.knobs.omit_last_modified.store(true, Ordering::SeqCst);
+    mock.knobs.touch();
+    let ar = sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(ar.last_modified.is_none());
+    assert_eq!(ar.len(), 5);
+}
+
+#[tokio::test]
+async fn skips_unchanged_snapshots_the_server_sends_anyway() {
+    let mock = spawn(Knobs::default());
+    mock.knobs
+        .ignore_if_modified_since
+        .store(true, Ordering::SeqCst);
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+    let sessions = SessionManager::new(settings(&mock.url, data_dir));
+
+    let ar = sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .unwrap();
+    let archive = data_dir
+        .join("eu/1403")
+        .join(format!("{}.zst", ar.archived_as.unwrap()));
+    std::fs::remove_file(&archive).unwrap();
+
+    // Sent in full, but neither parsed nor archived again
+    assert!(sessions
+        .auctions(Market::Realm(DRAENOR))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!archive.exists());
+    assert_eq!(mock.knobs.requests.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn fetches_item_metadata() {
+    let mock = spawn(Knobs::default());
+    let dir = tempfile::tempdir().unwrap();
+    let sessions = SessionManager::new(settings(&mock.url, dir.path()));
+
+    let item = sessions.item(Region::Eu, 109119).await.unwrap().unwrap();
+    assert_eq!(item.en_us, "True Iron Ore");
+    assert_eq!(item.names["de_DE"], "Echteisenerz");
+    assert_eq!(item.quality.as_deref(), Some("COMMON"));
+    assert_eq!((item.class_id, item.subclass_id), (Some(7), Some(7)));
+    assert_eq!(item.stackable, Some(true));
+    assert!(sessions.item(Region::Eu, 1).await.unwrap().is_none());
+
+    let pet = sessions.pet(Region::Eu, 39).await.unwrap().unwrap();
+    assert_eq!(pet.name("de_DE"), "Mechanisches Eichhörnchen");
+    assert!(sessions.pet(Region::Eu, 1).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn recovers_from_faults() {
+    let mock = spawn(Knobs::default());
+    let dir = tempfile::tempdir().unwrap();
+    let sessions = SessionManager::new(settings(&mock.url, dir.path()));
+
+    mock.knobs.unauthorized.store(1, Ordering::SeqCst);
+    mock.knobs.rate_limited.store(1, Ordering::SeqCst);
+    mock.knobs.server_errors.store(1, Ordering::SeqCst);
+    let started = Instant::now();
+    let ar = sessions.auctions(Market::Realm(DRAENOR)).await.unwrap();
+    assert_eq!(ar.unwrap().len(), 5);
+    assert!(
+        started.elapsed() >= Duration::from_secs(1),
+        "Retry-After ignored"
+    );
+
+    mock.knobs.touch();
+    mock.knobs.server_errors.store(3, Ordering::SeqCst);
+    match sessions.auctions(Market::Realm(DRAENOR)).await {
+        Err(Error::RetriesExhausted { attempts, .. }) => assert_eq!(attempts, 3),
+        res => panic!("Expected retries to run out, got {:?}", res.map(|_| ())),
+    }
+
+    mock.knobs.reject_credentials.store(true, Ordering::SeqCst);
+    let rejected_dir = tempfile::tempdir().unwrap();
+    let rejected = SessionManager::new(settings(&mock.url, rejected_dir.path()));
+    match rejected.session(Region::Eu).await {
+        Err(Error::ApiFailure(_)) => {}
+        res => panic!("Expected authentication to fail, got {:?}", res.map(|_| ())),
+    }
+}
+
+#[test]
+fn sync_archives_snapshots() {
+    let mock = spawn(Knobs::default());
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    std::fs::write(
+        dir.join("Settings.toml"),
+        format!(
+            r#"
+client_id = "client"
+client_secret = "secret"
+realm_id = 1403
+data_dir = "{data_dir}"
+delay_mins = 1
+save_flexbuffer = false
+db_host = "localhost"
+api_url = "{url}"
+oauth_url = "{url}"
+"#,
+            data_dir = dir.join("data").to_str().unwrap(),
+            url = mock.url
+        ),
+    )
+    .unwrap();
+
+    let mut sync = Command::new(env!("CARGO_BIN_EXE_waw"))
+        .current_dir(dir)
+        .arg("sync")
+        .arg("--ephemeral")
+        .spawn()
+        .unwrap();
+    let archived = |market: &str| {
+        std::fs::read_dir(dir.join("data").join("eu").join(market))
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .any(|e| e.path().extension() == Some("zst".as_ref()))
+            })
+            .unwrap_or(false)
+    };
+    let started = Instant::now();
+    while !(archived("1403") && archived("commodities")) {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "Nothing archived"
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    sync.kill().unwrap();
+    sync.wait().unwrap();
+}
@@ -0,0 +1,294 @@
+//! A stand-in for the battle.net APIs `waw` uses, serving fixed snapshots so syncing can be
+//! developed and tested without credentials or network access.
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use chrono::{DateTime, TimeZone, Utc};
+use log::info;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The token handed out by the OAuth endpoint and expected by every other one
+pub const ACCESS_TOKEN: &str = "mock-access-token";
+
+const AUCTIONS: &str = include_str!("../fixtures/auctions.json");
+const COMMODITIES: &str = include_str!("../fixtures/commodities.json");
+const ITEMS: &str = include_str!("../fixtures/items.json");
+const PETS: &str = include_str!("../fixtures/pets.json");
+
+/// Faults to inject, adjustable while the server runs.
+///
+/// The counters apply to the next that many auction, commodity, item or pet requests.
+#[derive(Debug)]
+pub struct Knobs {
+    /// Refuse the client credentials with a 401
+    pub reject_credentials: AtomicBool,
+    /// Respond 401, as if the token had expired
+    pub unauthorized: AtomicU32,
+    /// Respond 429 with a `Retry-After` of a second
+    pub rate_limited: AtomicU32,
+    /// Respond 500
+    pub server_errors: AtomicU32,
+    /// Milliseconds to wait before every response
+    pub delay_ms: AtomicU64,
+    /// When the snapshots were last updated, in seconds since the epoch
+    pub last_modified: AtomicI64,
+    /// Leave the `Last-Modified` header off snapshots
+    pub omit_last_modified: AtomicBool,
+    /// Send snapshots whatever their `If-Modified-Since`, as some servers do
+    pub ignore_if_modified_since: AtomicBool,
+    /// Requests received, of any kind
+    pub requests: AtomicU32,
+}
+
+impl Default for Knobs {
+    fn default() -> Self {
+        Self {
+            reject_credentials: AtomicBool::new(false),
+            unauthorized: AtomicU32::new(0),
+            rate_limited: AtomicU32::new(0),
+            server_errors: AtomicU32::new(0),
+            delay_ms: AtomicU64::new(0),
+            last_modified: AtomicI64::new(1_600_162_262),
+            omit_last_modified: AtomicBool::new(false),
+            ignore_if_modified_since: AtomicBool::new(false),
+            requests: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Knobs {
+    /// Knobs set from `MOCK_`-prefixed environment variables, e.g. `MOCK_RATE_LIMITED=3`
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+        let knobs = Self::default();
+        if let Some(v) = var("MOCK_REJECT_CREDENTIALS") {
+            knobs.reject_credentials.store(v, Ordering::SeqCst);
+        }
+        if let Some(v) = var("MOCK_UNAUTHORIZED") {
+            knobs.unauthorized.store(v, Ordering::SeqCst);
+        }
+        if let Some(v) = var("MOCK_RATE_LIMITED") {
+            knobs.rate_limited.store(v, Ordering::SeqCst);
+        }
+        if let Some(v) = var("MOCK_SERVER_ERRORS") {
+            knobs.server_errors.store(v, Ordering::SeqCst);
+        }
+        if let Some(v) = var("MOCK_DELAY_MS") {
+            knobs.delay_ms.store(v, Ordering::SeqCst);
+        }
+        knobs
+    }
+
+    /// Mark the snapshots as updated now, so the next fetch gets a fresh one
+    pub fn touch(&self) {
+        self.last_modified.fetch_add(3600, Ordering::SeqCst);
+    }
+
+    fn last_modified(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.last_modified.load(Ordering::SeqCst), 0)
+            .unwrap()
+    }
+
+    /// Count one off `counter`, telling whether it had any left
+    fn take(counter: &AtomicU32) -> bool {
+        counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// The response to send instead of the real one, if a fault is due
+    fn fault(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        let bearer = format!("Bearer {}", ACCESS_TOKEN);
+        let authorised = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            == Some(bearer.as_str());
+        if !authorised || Self::take(&self.unauthorized) {
+            Some(HttpResponse::Unauthorized().finish())
+        } else if Self::take(&self.rate_limited) {
+            Some(
+                HttpResponse::TooManyRequests()
+                    .header("Retry-After", "1")
+                    .finish(),
+            )
+        } else if Self::take(&self.server_errors) {
+            Some(HttpResponse::InternalServerError().finish())
+        } else {
+            None
+        }
+    }
+}
+
+/// A running mock server
+pub struct Mock {
+    /// The base URL to use for both the OAuth and Game Data APIs, e.g. `http://127.0.0.1:41234`
+    pub url: String,
+    pub knobs: Arc<Knobs>,
+}
+
+/// Start serving on a free local port, in a thread of its own
+pub fn spawn(knobs: Knobs) -> Mock {
+    let knobs = Arc::new(knobs);
+    let data = knobs.clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let sys = actix_rt::System::new("waw-mock");
+        let server = HttpServer::new(move || App::new().data(data.clone()).configure(routes))
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .expect("Failed to bind mock server");
+        tx.send(server.addrs()[0])
+            .expect("Mock server receiver dropped");
+        server.run();
+        sys.run()
+    });
+    let addr = rx.recv().expect("Mock server failed to start");
+    Mock {
+        url: format!("http://{}", addr),
+        knobs,
+    }
+}
+
+/// The mock's routes, which expect an `Arc<Knobs>` in the app data
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/oauth/token", web::post().to(token))
+        .route(
+            "/data/wow/connected-realm/{realm}/auctions",
+            web::get().to(auctions),
+        )
+        .route("/data/wow/auctions/commodities", web::get().to(commodities))
+        .route("/data/wow/item/{item}", web::get().to(item))
+        .route("/data/wow/pet/{pet}", web::get().to(pet));
+}
+
+async fn delay(knobs: &Knobs) {
+    knobs.requests.fetch_add(1, Ordering::SeqCst);
+    let ms = knobs.delay_ms.load(Ordering::SeqCst);
+    if ms > 0 {
+        actix_rt::time::delay_for(std::time::Duration::from_millis(ms)).await;
+    }
+}
+
+async fn token(knobs: web::Data<Arc<Knobs>>) -> HttpResponse {
+    delay(&knobs).await;
+    if knobs.reject_credentials.load(Ordering::SeqCst) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "unauthorized",
+            "error_description": "Bad credentials"
+        }));
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "access_token": ACCESS_TOKEN,
+        "token_type": "bearer",
+        "expires_in": 86399,
+        "sub": "mock-client"
+    }))
+}
+
+/// Serve a snapshot, or a 304 if it's no newer than `If-Modified-Since`
+fn snapshot(knobs: &Knobs, req: &HttpRequest, mut body: serde_json::Value) -> HttpResponse {
+    let last_modified = knobs.last_modified();
+    let since = req
+        .headers()
+        .get("If-Modified-Since")
+        .filter(|_| !knobs.ignore_if_modified_since.load(Ordering::SeqCst))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    if let Some(since) = since {
+        if since >= last_modified {
+            return HttpResponse::NotModified().finish();
+        }
+    }
+    if let Some(realm) = req.match_info().get("realm") {
+        body["connected_realm"] = serde_json::json!({
+            "href": format!("https://eu.api.blizzard.com/data/wow/connected-realm/{}", realm)
+        });
+    }
+    let mut res = HttpResponse::Ok();
+    if !knobs.omit_last_modified.load(Ordering::SeqCst) {
+        res.header(
+            "Last-Modified",
+            last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        );
+    }
+    res.json(body)
+}
+
+async fn auctions(req: HttpRequest, knobs: web::Data<Arc<Knobs>>) -> HttpResponse {
+    delay(&knobs).await;
+    info!("Auctions: {}", req.uri());
+    match knobs.fault(&req) {
+        Some(res) => res,
+        None => snapshot(&knobs, &req, fixture(AUCTIONS)),
+    }
+}
+
+async fn commodities(req: HttpRequest, knobs: web::Data<Arc<Knobs>>) -> HttpResponse {
+    delay(&knobs).await;
+    info!("Commodities: {}", req.uri());
+    match knobs.fault(&req) {
+        Some(res) => res,
+        None => snapshot(&knobs, &req, fixture(COMMODITIES)),
+    }
+}
+
+#[derive(Deserialize)]
+struct LocaleQuery {
+    locale: Option<String>,
+}
+
+/// An item, its name in every locale unless `locale` picks one, as the real API does
+async fn item(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    query: web::Query<LocaleQuery>,
+    knobs: web::Data<Arc<Knobs>>,
+) -> HttpResponse {
+    document(&req, ITEMS, *id, &query, &knobs).await
+}
+
+/// A battle pet species, named like an item
+async fn pet(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    query: web::Query<LocaleQuery>,
+    knobs: web::Data<Arc<Knobs>>,
+) -> HttpResponse {
+    document(&req, PETS, *id, &query, &knobs).await
+}
+
+/// The document with the given id from a fixture of them
+async fn document(
+    req: &HttpRequest,
+    fixture_json: &str,
+    id: u64,
+    query: &LocaleQuery,
+    knobs: &Knobs,
+) -> HttpResponse {
+    delay(knobs).await;
+    if let Some(res) = knobs.fault(req) {
+        return res;
+    }
+    let documents = fixture(fixture_json);
+    let found = documents
+        .as_array()
+        .and_then(|documents| documents.iter().find(|d| d["id"] == id));
+    match (found, &query.locale) {
+        (None, _) => HttpResponse::NotFound().finish(),
+        (Some(doc), None) => HttpResponse::Ok().json(doc),
+        (Some(doc), Some(locale)) => {
+            let mut doc = doc.clone();
+            doc["name"] = doc["name"][locale.as_str()].clone();
+            HttpResponse::Ok().json(doc)
+        }
+    }
+}
+
+fn fixture(json: &str) -> serde_json::Value {
+    serde_json::from_str(json).expect("Invalid fixture")
+}
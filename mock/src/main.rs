@@ -0,0 +1,21 @@
+use actix_web::{App, HttpServer};
+use log::info;
+use std::sync::Arc;
+use waw_mock::{routes, Knobs};
+
+/// Serve the mock battle.net APIs on `MOCK_ADDR`, or 127.0.0.1:8090, with faults injected as
+/// configured by the other `MOCK_` variables. Point `WAW_API_URL` and `WAW_OAUTH_URL` at it.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+    );
+
+    let addr = std::env::var("MOCK_ADDR").unwrap_or_else(|_| "127.0.0.1:8090".to_string());
+    let knobs = Arc::new(Knobs::from_env());
+    info!("Serving mock battle.net APIs on {} with {:?}", addr, knobs);
+    HttpServer::new(move || App::new().data(knobs.clone()).configure(routes))
+        .bind(&addr)?
+        .run()
+        .await
+}
@@ -3,62 +3,54 @@ use actix::{Actor, Addr, Context, Message};
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
-use waw::realm::Item;
+use waw::db::PriceStore;
+use waw::realm::{
+    variant_key, Item, Market, MarketStats, Pet, PetSpecies, Region, Target, DEFAULT_LOCALE,
+    LOCALES, PET_CAGE,
+};
 use waw::Settings;
 
 pub struct Server {
-    settings: Settings,
     item_actor: Addr<ItemActor>,
+    /// The realm charted when a request doesn't name one
+    default_target: Target,
 }
 
-/// A time series of of prices for an item
+/// A time series of of prices for an item, or for a battle pet species by its species id
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Series {
     id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
     name: String,
     prices: Vec<ItemSnapshot>,
     min: (i64, u64),
     max: (i64, u64),
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct ItemSnapshots {
-    snapshots: Vec<ItemSnapshot>,
-}
-
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ItemSnapshot {
     pub ts: i64,
     pub value: u64,
 }
 
-impl redis::FromRedisValue for ItemSnapshots {
-    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-        match *v {
-            redis::Value::Bulk(ref values) => Ok(ItemSnapshots {
-                snapshots: redis::FromRedisValue::from_redis_values(values)?
-                    .into_iter()
-                    .map(|v: (i64, u64)| ItemSnapshot {
-                        ts: v.0,
-                        value: v.1,
-                    })
-                    .collect(),
-            }),
-            _ => Err(redis::RedisError::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "no_range_data",
-            ))),
-        }
-    }
+/// An item found by a search, with the variants it has prices stored for
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemMatch {
+    #[serde(flatten)]
+    item: Item,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<String>,
 }
 
+/// Serves item, price and watchlist look-ups from the configured store
 struct ItemActor {
-    con: redis::Connection,
+    store: Box<dyn PriceStore>,
 }
 
 impl ItemActor {
-    pub fn new(con: redis::Connection) -> Self {
-        Self { con: con }
+    pub fn new(store: Box<dyn PriceStore>) -> Self {
+        Self { store }
     }
 }
 
@@ -70,9 +62,99 @@ impl actix::Actor for ItemActor {
 #[rtype(result = "Option<Item>")]
 struct GetItem(u64);
 
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<ItemMatch>, waw::Error>")]
+struct SearchItems {
+    name: String,
+    locale: String,
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Option<PetSpecies>, waw::Error>")]
+struct GetPet(u32);
+
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<(i64, u64)>, waw::Error>")]
+struct GetRange(String);
+
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<u64>, waw::Error>")]
+struct GetWatchlist;
+
+/// Put an item on the watchlist, if it's known, returning it and whether it wasn't on it already
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Option<(Item, bool)>, waw::Error>")]
+struct AddToWatchlist(WatchlistItem);
+
+/// Take an item off the watchlist, returning whether it was on it
+#[derive(Debug, Message)]
+#[rtype(result = "Result<bool, waw::Error>")]
+struct RemoveFromWatchlist(u64);
+
+/// An item to watch, by its id or by its name in `locale`
+#[derive(Debug, Deserialize)]
+struct WatchlistItem {
+    id: Option<u64>,
+    name: Option<String>,
+    /// The locale `name` is in, e.g. `de_DE`; English by default
+    locale: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct ItemSearch {
     p: String,
+    /// The locale to match names in, e.g. `de_DE`; English by default
+    locale: Option<String>,
+}
+
+/// Which kind of auction house a series is for
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MarketKind {
+    Realm,
+    Commodities,
+}
+
+impl Default for MarketKind {
+    fn default() -> Self {
+        MarketKind::Realm
+    }
+}
+
+#[derive(Deserialize)]
+struct SeriesQuery {
+    /// The auction house to chart, defaulting to the realm's
+    #[serde(default)]
+    market: MarketKind,
+    region: Option<Region>,
+    realm: Option<u16>,
+    /// The locale to name the item in
+    locale: Option<String>,
+    /// The variant to chart, e.g. `b1487.6646`, rather than the plain item
+    variant: Option<String>,
+}
+
+impl SeriesQuery {
+    /// The requested market, filling in anything missing from the default target
+    fn market(&self, default: &Target) -> Market {
+        let region = self.region.unwrap_or(default.region);
+        match self.market {
+            MarketKind::Commodities => Market::Commodities(region),
+            MarketKind::Realm => Market::Realm(Target {
+                region,
+                realm_id: self.realm.unwrap_or(default.realm_id),
+            }),
+        }
+    }
+
+    /// The key of the price series for the item, or its variant, in the requested market
+    fn key(&self, default: &Target, item_id: u64, variant: Option<&str>) -> String {
+        format!(
+            "auc:{}:{}",
+            self.market(default).namespace(),
+            variant_key(item_id, variant)
+        )
+    }
 }
 
 impl Handler<GetItem> for ItemActor {
@@ -80,108 +162,244 @@ impl Handler<GetItem> for ItemActor {
 
     fn handle(&mut self, msg: GetItem, _: &mut Self::Context) -> Self::Result {
         info!("Finding item {:?}", msg);
-        waw::db::get_item_metadata(&mut self.con, msg.0).expect("Actor failed item metadata lookup")
+        self.store
+            .get_item_metadata(msg.0)
+            .expect("Actor failed item metadata lookup")
+    }
+}
+
+impl Handler<SearchItems> for ItemActor {
+    type Result = Result<Vec<ItemMatch>, waw::Error>;
+
+    fn handle(&mut self, msg: SearchItems, _: &mut Self::Context) -> Self::Result {
+        let ids = self.store.search_ids_for_item(&msg.locale, &msg.name)?;
+        let mut matches = vec![];
+        for id in ids {
+            if let Some(item) = self.store.get_item_metadata(id)? {
+                let variants = self.store.get_variants(id)?;
+                matches.push(ItemMatch { item, variants });
+            }
+        }
+        Ok(matches)
+    }
+}
+
+impl Handler<GetPet> for ItemActor {
+    type Result = Result<Option<PetSpecies>, waw::Error>;
+
+    fn handle(&mut self, msg: GetPet, _: &mut Self::Context) -> Self::Result {
+        self.store.get_pet(msg.0)
+    }
+}
+
+impl Handler<GetRange> for ItemActor {
+    type Result = Result<Vec<(i64, u64)>, waw::Error>;
+
+    fn handle(&mut self, msg: GetRange, _: &mut Self::Context) -> Self::Result {
+        self.store.get_range(&msg.0)
+    }
+}
+
+impl Handler<GetWatchlist> for ItemActor {
+    type Result = Result<Vec<u64>, waw::Error>;
+
+    fn handle(&mut self, _: GetWatchlist, _: &mut Self::Context) -> Self::Result {
+        self.store.get_watchlist()
+    }
+}
+
+impl Handler<AddToWatchlist> for ItemActor {
+    type Result = Result<Option<(Item, bool)>, waw::Error>;
+
+    fn handle(&mut self, msg: AddToWatchlist, _: &mut Self::Context) -> Self::Result {
+        let item = match (msg.0.id, msg.0.name) {
+            (Some(id), _) => self.store.get_item_metadata(id)?,
+            (None, Some(name)) => {
+                let locale = msg.0.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+                self.store.get_item_metadata_by_name(locale, &name)?
+            }
+            (None, None) => None,
+        };
+        match item {
+            Some(item) => {
+                let added = self.store.add_to_watchlist(&[item.id])? > 0;
+                Ok(Some((item, added)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Handler<RemoveFromWatchlist> for ItemActor {
+    type Result = Result<bool, waw::Error>;
+
+    fn handle(&mut self, msg: RemoveFromWatchlist, _: &mut Self::Context) -> Self::Result {
+        self.store.remove_from_watchlist(msg.0)
     }
 }
 
 async fn get_watchlist(server: web::Data<Server>, _: HttpRequest) -> HttpResponse {
-    let mut con = waw::db::redis_connect(server.settings.db_host.clone())
-        .unwrap()
-        .1;
-    let watchlist: Vec<u64> = redis::cmd("SMEMBERS")
-        .arg("watchlist")
-        .query(&mut con)
-        .unwrap();
-    HttpResponse::Ok().json(watchlist)
+    match server.item_actor.send(GetWatchlist).await {
+        Ok(Ok(watchlist)) => HttpResponse::Ok().json(watchlist),
+        Ok(Err(e)) => {
+            error!("Watchlist lookup failed: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("{:?}", e))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+/// Watch an item by its id or name, responding with it: `201 Created` if it's newly watched
+async fn add_to_watchlist(
+    server: web::Data<Server>,
+    body: web::Json<WatchlistItem>,
+) -> HttpResponse {
+    let body = body.into_inner();
+    if body.id.is_some() == body.name.is_some() {
+        return HttpResponse::BadRequest().body("Expected one of id or name");
+    }
+    let locale = body.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+    if !LOCALES.contains(&locale) {
+        return HttpResponse::BadRequest().body(format!("No such locale {}", locale));
+    }
+    match server.item_actor.send(AddToWatchlist(body)).await {
+        Ok(Ok(Some((item, true)))) => {
+            info!("Watching item {}", item.id);
+            HttpResponse::Created().json(item)
+        }
+        Ok(Ok(Some((item, false)))) => HttpResponse::Ok().json(item),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("No such item"),
+        Ok(Err(e)) => {
+            error!("Watchlist update failed: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("{:?}", e))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+async fn remove_from_watchlist(server: web::Data<Server>, req: HttpRequest) -> HttpResponse {
+    let item_id = match req.match_info().get("item").map(str::parse) {
+        Some(Ok(id)) => id,
+        _ => return HttpResponse::NotFound().body("Invalid item identifier"),
+    };
+    match server.item_actor.send(RemoveFromWatchlist(item_id)).await {
+        Ok(Ok(true)) => {
+            info!("Stopped watching item {}", item_id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(Ok(false)) => HttpResponse::NotFound().body("Item isn't on the watchlist"),
+        Ok(Err(e)) => {
+            error!("Watchlist update failed: {:?}", e);
+            HttpResponse::InternalServerError().body(format!("{:?}", e))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
 }
 
 async fn search_items(server: web::Data<Server>, search: web::Query<ItemSearch>) -> HttpResponse {
-    let mut con = waw::db::redis_connect(server.settings.db_host.clone())
-        .unwrap()
-        .1;
-    match waw::db::search_ids_for_item(&mut con, search.p.clone()) {
-        Ok(i) => HttpResponse::Ok().json::<Vec<Item>>(
-            i.into_iter()
-                .map(|id_list| {
-                    id_list
-                        .into_iter()
-                        .map(|id| {
-                            waw::db::get_item_metadata(&mut con, id.parse().unwrap())
-                                .unwrap()
-                                .unwrap()
-                        })
-                        .collect::<Vec<Item>>()
-                })
-                .flatten()
-                .collect(),
-        ),
-        Err(e) => HttpResponse::NotFound().body(format!("{}", e)),
+    let locale = search.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+    if !LOCALES.contains(&locale) {
+        return HttpResponse::BadRequest().body(format!("No such locale {}", locale));
+    }
+    let msg = SearchItems {
+        name: search.p.clone(),
+        locale: locale.to_string(),
+    };
+    match server.item_actor.send(msg).await {
+        Ok(Ok(items)) => HttpResponse::Ok().json::<Vec<ItemMatch>>(items),
+        Ok(Err(e)) => HttpResponse::NotFound().body(format!("{:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+/// The points of the series `key`, or of its `stat` series, summarised as a `Series`
+async fn range_series(
+    server: &Server,
+    key: String,
+    stat: Option<&str>,
+    id: u64,
+    variant: Option<String>,
+    name: String,
+) -> HttpResponse {
+    let key = match stat {
+        Some(stat) => format!("{}:{}", key, stat),
+        None => key,
+    };
+    match server.item_actor.send(GetRange(key)).await {
+        Ok(Ok(points)) => {
+            info!("Handling range for {}", id);
+            let prices: Vec<ItemSnapshot> = points
+                .into_iter()
+                .map(|(ts, v)| ItemSnapshot { ts, value: v })
+                .collect();
+            let min = prices
+                .iter()
+                .min_by(|x, y| x.value.cmp(&y.value))
+                .map(|i| (i.ts, i.value))
+                .unwrap_or((0, 0));
+            let max = prices
+                .iter()
+                .max_by(|x, y| x.value.cmp(&y.value))
+                .map(|i| (i.ts, i.value))
+                .unwrap_or((0, 0));
+            HttpResponse::Ok().json(Series {
+                id,
+                variant,
+                name,
+                min,
+                max,
+                prices,
+            })
+        }
+        Ok(Err(e)) => {
+            error!("Series lookup for {} failed: {:?}", id, e);
+            HttpResponse::InternalServerError()
+                .body(format!("Failure during series lookup: {:?}", e))
+        }
+        Err(e) => {
+            error!("Series lookup for {} failed: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Failure during series lookup: {}", e))
+        }
     }
 }
 
-async fn get_series(server: web::Data<Server>, req: HttpRequest) -> HttpResponse {
+/// The requested statistic, if it's a known one
+fn valid_stat(req: &HttpRequest) -> Result<Option<&str>, HttpResponse> {
+    match req.match_info().get("stat") {
+        Some(name) if !MarketStats::NAMES.contains(&name) => {
+            Err(HttpResponse::NotFound().body(format!("No such statistic {}", name)))
+        }
+        stat => Ok(stat),
+    }
+}
+
+async fn get_series(
+    server: web::Data<Server>,
+    query: web::Query<SeriesQuery>,
+    req: HttpRequest,
+) -> HttpResponse {
     let item = req.match_info().get("item");
-    info!("Item lookup {}", item.unwrap_or("No item"));
+    let stat = match valid_stat(&req) {
+        Ok(stat) => stat,
+        Err(res) => return res,
+    };
+    info!("Item lookup {} {:?}", item.unwrap_or("No item"), stat);
     if let Some(id) = item {
-        let mut con = waw::db::redis_connect(server.settings.db_host.clone())
-            .unwrap()
-            .1;
-
         if let Ok(item_id) = id.parse() {
             let item_lookup = server.item_actor.send(GetItem(item_id)).await;
             if let Ok(Some(item_md)) = item_lookup {
                 info!("Found item metadata: {:?}", item_md);
-
-                match waw::db::get_range(&mut con, &item_md) {
-                    Ok(x) => {
-                        info!("Handling range for {}", item_id);
-                        match x {
-                            redis::Value::Bulk(v) => {
-                                let prices: Vec<ItemSnapshot> = v
-                                    .iter()
-                                    .flat_map(|ref l| match l {
-                                        redis::Value::Bulk(vl) => Some((
-                                            redis::from_redis_value(&vl[0]).unwrap(),
-                                            redis::from_redis_value(&vl[1]).unwrap(),
-                                        )),
-                                        _ => None,
-                                    })
-                                    .map(|(ts, v)| ItemSnapshot { ts, value: v })
-                                    .collect();
-                                let min = prices
-                                    .iter()
-                                    .min_by(|x, y| x.value.cmp(&y.value))
-                                    .map(|i| (i.ts, i.value))
-                                    .unwrap_or((0, 0));
-                                let max = prices
-                                    .iter()
-                                    .max_by(|x, y| x.value.cmp(&y.value))
-                                    .map(|i| (i.ts, i.value))
-                                    .unwrap_or((0, 0));
-                                HttpResponse::Ok().json(Series {
-                                    id: item_id,
-                                    name: item_md.en_us.clone(),
-                                    min: min,
-                                    max: max,
-                                    prices: prices,
-                                })
-                            }
-                            v => {
-                                error!(
-                                    "Unexpected redis response for series lookup for {}: {:?}",
-                                    item_id, v
-                                );
-                                HttpResponse::InternalServerError()
-                                    .body(format!("Unknown redis response for {}", item_id))
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Series lookup for {} failed: {}", item_id, e);
-                        HttpResponse::InternalServerError()
-                            .body(format!("Failure during series lookup: {}", e))
-                    }
-                }
+                range_series(
+                    &server,
+                    query.key(&server.default_target, item_md.id, query.variant.as_deref()),
+                    stat,
+                    item_id,
+                    query.variant.clone(),
+                    item_md
+                        .name(query.locale.as_deref().unwrap_or(DEFAULT_LOCALE))
+                        .to_string(),
+                )
+                .await
             } else {
                 error!("No item {} found via actor lookup", item_id);
                 HttpResponse::NotFound().body("No such item")
@@ -194,30 +412,94 @@ async fn get_series(server: web::Data<Server>, req: HttpRequest) -> HttpResponse
     }
 }
 
+/// The prices of caged battle pets of a species at the given level and quality
+async fn get_pet_series(
+    server: web::Data<Server>,
+    query: web::Query<SeriesQuery>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let stat = match valid_stat(&req) {
+        Ok(stat) => stat,
+        Err(res) => return res,
+    };
+    fn param<T: std::str::FromStr>(req: &HttpRequest, name: &str) -> Option<T> {
+        req.match_info().get(name)?.parse().ok()
+    }
+    let pet = match (
+        param(&req, "species"),
+        param(&req, "level"),
+        param(&req, "quality"),
+    ) {
+        (Some(species_id), Some(level), Some(quality_id)) => Pet {
+            species_id,
+            level,
+            quality_id,
+        },
+        _ => return HttpResponse::NotFound().body("Invalid pet identifier"),
+    };
+    info!("Pet lookup {:?} {:?}", pet, stat);
+    match server.item_actor.send(GetPet(pet.species_id)).await {
+        Ok(Ok(Some(species))) => {
+            range_series(
+                &server,
+                query.key(&server.default_target, PET_CAGE, Some(&pet.variant())),
+                stat,
+                species.id as u64,
+                Some(pet.variant()),
+                species
+                    .name(query.locale.as_deref().unwrap_or(DEFAULT_LOCALE))
+                    .to_string(),
+            )
+            .await
+        }
+        Ok(Ok(None)) => HttpResponse::NotFound().body("No such pet species"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
 
-    HttpServer::new(move || {
-        let settings = Settings::new().unwrap();
-        let mut ct = waw::db::redis_connect(settings.db_host.clone()).unwrap();
-        waw::db::store_watchlist(&mut ct.1, "ref-data/init.json")
-            .expect("Couldn't store watchlist");
-        waw::db::store_item_metadata(&mut ct.1, "ref-data/items.csv").expect("Couldn't store item metadata");
-        let ia = ItemActor::new(ct.1).start();
+    let settings = Settings::new().unwrap();
+    let default_target = *settings
+        .targets()
+        .first()
+        .expect("No realms configured in targets or realm_id");
+    let mut store = waw::db::open_store(&settings).unwrap();
+    // Only seeds a store the first time, so restarts keep any changes to the watchlist
+    waw::db::store_watchlist(store.as_mut(), "ref-data/init.json")
+        .expect("Couldn't store watchlist");
+    waw::db::store_item_metadata(store.as_mut(), "ref-data/items.csv")
+        .expect("Couldn't store item metadata");
+    // One actor, and so one store, shared by every worker
+    let ia = ItemActor::new(store).start();
 
+    HttpServer::new(move || {
         App::new()
             .wrap(middleware::Compress::default())
             .wrap(actix_cors::Cors::new().supports_credentials().finish())
             .data(Server {
-                settings: Settings::new().unwrap(),
-                item_actor: ia,
+                item_actor: ia.clone(),
+                default_target,
             })
             .route("/items", web::get().to(search_items))
             .route("/series/{item}", web::get().to(get_series))
+            .route("/series/{item}/{stat}", web::get().to(get_series))
+            .route(
+                "/pets/{species}/{level}/{quality}",
+                web::get().to(get_pet_series),
+            )
+            .route(
+                "/pets/{species}/{level}/{quality}/{stat}",
+                web::get().to(get_pet_series),
+            )
             .route("/watchlist", web::get().to(get_watchlist))
+            .route("/watchlist", web::post().to(add_to_watchlist))
+            .route("/watchlist/{item}", web::delete().to(remove_from_watchlist))
     })
     .bind("0.0.0.0:8080")?
     .run()
@@ -228,30 +510,104 @@ async fn main() -> std::io::Result<()> {
 mod tests {
     use super::*;
     use actix_web::{http::StatusCode, test, web, App};
+    use waw::actors::AuctionRow;
+    use waw::db::memory::MemoryStore;
+
+    const DRAENOR: Target = Target {
+        region: Region::Eu,
+        realm_id: 1403,
+    };
+
+    /// A store holding the seeded watchlist with metadata and a price for each item
+    fn test_store() -> MemoryStore {
+        let mut store = MemoryStore::default();
+        info!(
+            "Stored: {} watchlist",
+            waw::db::store_watchlist(&mut store, "../ref-data/init.json")
+                .expect("Couldn't store watchlist")
+        );
+        let watchlist = store.get_watchlist().unwrap();
+        let mut items: Vec<Item> = watchlist
+            .iter()
+            .map(|id| Item {
+                id: *id,
+                en_us: format!("Item {}", id),
+                ..Default::default()
+            })
+            .collect();
+        items.push(Item {
+            id: 109119,
+            en_us: "True Iron Ore".to_string(),
+            names: vec![("de_DE".to_string(), "Echteisenerz".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        });
+        store.store_items(items).unwrap();
+        for id in watchlist {
+            let row = AuctionRow {
+                market: Market::Realm(DRAENOR),
+                item_id: id,
+                auction_id: 1,
+                quantity: 1,
+                unit_price: 100,
+                variant: None,
+            };
+            store.store_auction(&row, 1_600_000_000).unwrap();
+            store
+                .store_point(
+                    &format!("auc:eu:1403:item:{}:median", id),
+                    1_600_000_000,
+                    120,
+                )
+                .unwrap();
+        }
+        let row = AuctionRow {
+            market: Market::Realm(DRAENOR),
+            item_id: 109119,
+            auction_id: 2,
+            quantity: 1,
+            unit_price: 5000,
+            variant: Some("b1487.6646".to_string()),
+        };
+        store.store_auction(&row, 1_600_000_000).unwrap();
+
+        store
+            .store_pets(vec![PetSpecies {
+                id: 39,
+                en_us: "Mechanical Squirrel".to_string(),
+                names: vec![("de_DE".to_string(), "Mechanisches Eichhörnchen".to_string())]
+                    .into_iter()
+                    .collect(),
+            }])
+            .unwrap();
+        let row = AuctionRow {
+            market: Market::Realm(DRAENOR),
+            item_id: PET_CAGE,
+            auction_id: 3,
+            quantity: 1,
+            unit_price: 90000,
+            variant: Some("s39.l25.q3".to_string()),
+        };
+        store.store_auction(&row, 1_600_000_000).unwrap();
+        store
+    }
 
     #[actix_rt::test]
     async fn test_search_items() {
         env_logger::init_from_env(
             env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
         );
-        let settings = Settings::from("../Settings").unwrap();
-
-        // This only needs to be done once, it's slow
-        let (_, mut con) = waw::db::redis_connect(settings.db_host.clone()).unwrap();
-        info!("Stored: {} watchlist", waw::db::store_watchlist(&mut con, "../ref-data/init.json")
-            .expect("Couldn't store watchlist"));
-        waw::db::store_item_metadata(&mut con, "../ref-data/items.csv")
-            .expect("Couldn't store item metadata");
+        let store = test_store();
 
         //FIXME refactor to a function for reuse in test & main
         let srv = test::start(move || {
-            let mut ct = waw::db::redis_connect(settings.db_host.clone()).unwrap();
-            let ia = ItemActor::new(ct.1).start();
+            let ia = ItemActor::new(Box::new(store.clone())).start();
 
             App::new()
                 .data(Server {
-                    settings: Settings::from("../Settings").unwrap(),
                     item_actor: ia,
+                    default_target: DRAENOR,
                 })
                 .route("/items", web::get().to(search_items))
         });
@@ -274,24 +630,35 @@ mod tests {
                 panic!("Items lookup failed: {}", e);
             }
         }
+
+        let mut dcr = srv
+            .get("/items?p=echteisen&locale=de_DE")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(dcr.status(), StatusCode::OK);
+        let items: Vec<ItemMatch> = dcr.json().await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].item.name("de_DE"), "Echteisenerz");
+        assert_eq!(items[0].variants, vec!["b1487.6646".to_string()]);
+
+        let ucr = srv.get("/items?p=echt&locale=xx_XX").send().await;
+        assert_eq!(ucr.unwrap().status(), StatusCode::BAD_REQUEST);
     }
 
     #[actix_rt::test]
     async fn test_symbols_get_item_e2e() {
-        let settings = Settings::from("../Settings").unwrap();
-        let (_, mut con) = waw::db::redis_connect(settings.db_host.clone()).unwrap();
-        waw::db::store_item_metadata(&mut con, "../ref-data/items.csv")
-            .expect("Couldn't store item metadata");
+        let store = test_store();
         let srv = test::start(move || {
-            let ct = waw::db::redis_connect(settings.db_host.clone()).unwrap();
-            let ia = ItemActor::new(ct.1).start();
+            let ia = ItemActor::new(Box::new(store.clone())).start();
             App::new()
                 .data(Server {
-                    settings: Settings::from("../Settings").unwrap(),
                     item_actor: ia,
+                    default_target: DRAENOR,
                 })
                 .route("/items", web::get().to(search_items))
                 .route("/series/{item}", web::get().to(get_series))
+                .route("/series/{item}/{stat}", web::get().to(get_series))
                 .route("/watchlist", web::get().to(get_watchlist))
         });
 
@@ -314,11 +681,155 @@ mod tests {
                             panic!("lookup failed: {}", e);
                         }
                     };
+
+                    let mut mcr = srv
+                        .get(format!("/series/{}/median", sym))
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(mcr.status(), StatusCode::OK);
+                    let median: Series = mcr.json().await.unwrap();
+                    assert_eq!(median.max, (1_600_000_000, 120));
+
+                    let ucr = srv.get(format!("/series/{}/mode", sym)).send().await;
+                    assert_eq!(ucr.unwrap().status(), StatusCode::NOT_FOUND);
+
+                    let mut ccr = srv
+                        .get(format!("/series/{}?market=commodities", sym))
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(ccr.status(), StatusCode::OK);
+                    let commodity: Series = ccr.json().await.unwrap();
+                    assert!(commodity.prices.is_empty());
+
+                    let mut ocr = srv
+                        .get(format!("/series/{}?region=us&realm=3678", sym))
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(ocr.status(), StatusCode::OK);
+                    let other: Series = ocr.json().await.unwrap();
+                    assert!(other.prices.is_empty());
                 }
             }
             Err(e) => {
                 panic!("symbols lookup failed: {}", e);
             }
         }
+
+        let mut vcr = srv
+            .get("/series/109119?variant=b1487.6646")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(vcr.status(), StatusCode::OK);
+        let variant: Series = vcr.json().await.unwrap();
+        assert_eq!(variant.variant.as_deref(), Some("b1487.6646"));
+        assert_eq!(variant.max, (1_600_000_000, 5000));
+    }
+
+    #[actix_rt::test]
+    async fn test_pet_series() {
+        let store = test_store();
+        let srv = test::start(move || {
+            let ia = ItemActor::new(Box::new(store.clone())).start();
+            App::new()
+                .data(Server {
+                    item_actor: ia,
+                    default_target: DRAENOR,
+                })
+                .route(
+                    "/pets/{species}/{level}/{quality}",
+                    web::get().to(get_pet_series),
+                )
+                .route(
+                    "/pets/{species}/{level}/{quality}/{stat}",
+                    web::get().to(get_pet_series),
+                )
+        });
+
+        let mut pcr = srv.get("/pets/39/25/3?locale=de_DE").send().await.unwrap();
+        assert_eq!(pcr.status(), StatusCode::OK);
+        let series: Series = pcr.json().await.unwrap();
+        assert_eq!(series.id, 39);
+        assert_eq!(series.name, "Mechanisches Eichhörnchen");
+        assert_eq!(series.max, (1_600_000_000, 90000));
+
+        let mut lcr = srv.get("/pets/39/1/3").send().await.unwrap();
+        assert_eq!(lcr.status(), StatusCode::OK);
+        let low: Series = lcr.json().await.unwrap();
+        assert!(low.prices.is_empty());
+
+        for uri in &["/pets/2716/25/3", "/pets/39/25/3/mode", "/pets/39/300/3"] {
+            let res = srv.get(*uri).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_watchlist_updates() {
+        let store = test_store();
+        let srv = test::start(move || {
+            let ia = ItemActor::new(Box::new(store.clone())).start();
+
+            App::new()
+                .data(Server {
+                    item_actor: ia,
+                    default_target: DRAENOR,
+                })
+                .route("/watchlist", web::get().to(get_watchlist))
+                .route("/watchlist", web::post().to(add_to_watchlist))
+                .route("/watchlist/{item}", web::delete().to(remove_from_watchlist))
+        });
+
+        let res = srv.delete("/watchlist/109119").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = srv.delete("/watchlist/109119").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = srv.delete("/watchlist/ore").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let mut res = srv.get("/watchlist").send().await.unwrap();
+        let watchlist: Vec<u64> = res.json().await.unwrap();
+        assert_eq!(watchlist.len(), 9);
+        assert!(!watchlist.contains(&109119));
+
+        let mut res = srv
+            .post("/watchlist")
+            .send_json(&serde_json::json!({"name": "Echteisenerz", "locale": "de_DE"}))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let item: Item = res.json().await.unwrap();
+        assert_eq!(item.id, 109119);
+        let res = srv
+            .post("/watchlist")
+            .send_json(&serde_json::json!({"id": 109119}))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for (body, status) in [
+            (serde_json::json!({"id": 1}), StatusCode::NOT_FOUND),
+            (
+                serde_json::json!({"name": "No Such Ore"}),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                serde_json::json!({"id": 109119, "name": "True Iron Ore"}),
+                StatusCode::BAD_REQUEST,
+            ),
+            (serde_json::json!({}), StatusCode::BAD_REQUEST),
+            (
+                serde_json::json!({"name": "True Iron Ore", "locale": "xx_XX"}),
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let res = srv.post("/watchlist").send_json(&body).await.unwrap();
+            assert_eq!(res.status(), status, "{}", body);
+        }
+        let mut res = srv.get("/watchlist").send().await.unwrap();
+        let watchlist: Vec<u64> = res.json().await.unwrap();
+        assert_eq!(watchlist.len(), 10);
     }
 }